@@ -0,0 +1,17 @@
+pub mod zktc;
+
+pub use zktc::breakpoint::{
+    Breakpoint, BreakpointError, BreakpointKind, Breakpoints, Condition, WatchHit, WatchKind,
+};
+pub use zktc::bus::{Bus, BusError, Device, Permissions, Ram};
+pub use zktc::cpu::{Cpu, Exception};
+pub use zktc::expr::{Expr, ExprError};
+pub use zktc::inst::{decode, DecodeError, Instruction};
+pub use zktc::intc::InterruptController;
+pub use zktc::loader::{load_image, load_mem_file, parse_mem, Image, LoaderError};
+pub use zktc::machine::{Machine, MachineError, RegionKind, RegionSpec};
+pub use zktc::memory::{Alignment, Memory, MemoryAccess, MemoryError};
+pub use zktc::privilege::{Protection, Region};
+pub use zktc::trace::{JsonlSink, MemoryRecord, RegWrite, TextSink, TraceEvent, TraceSink};
+pub use zktc::uart::Uart;
+pub use zktc::{Error, FaultPolicy, StopReason, WritePolicy, Zktc};
@@ -1,33 +1,352 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-mod zktc;
+use clap::{Parser, Subcommand, ValueEnum};
 use rustyline::error::ReadlineError;
 use rustyline::DefaultEditor;
-use zktc::Error;
-use zktc::Zktc;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use zktc_emu::zktc::compare::{self, Columns, Comparator};
+use zktc_emu::zktc::intc;
+use zktc_emu::zktc::loader::{Format, Image};
+use zktc_emu::zktc::machine::{DeviceKind, Machine, RegionKind};
+use zktc_emu::zktc::memory::Alignment;
+use zktc_emu::zktc::privilege::{Protection, Region};
+use zktc_emu::zktc::snapshot::Snapshot;
+use zktc_emu::zktc::symbols::Symbols;
+use zktc_emu::zktc::timer::Clock;
+use zktc_emu::zktc::uart::{self, Uart};
+use zktc_emu::zktc::{asm, disasm, gdb, history, loader};
+use zktc_emu::{Error, FaultPolicy, JsonlSink, StopReason, TextSink, TraceSink, WritePolicy, Zktc};
+
+// exit codes of the run subcommand, 1 and 2 are left to anyhow and clap errors
+const EXIT_OK: i32 = 0;
+const EXIT_TEST_FAILED: i32 = 3;
+const EXIT_NO_VERDICT: i32 = 4;
+const EXIT_UNKNOWN_INSTRUCTION: i32 = 5;
+const EXIT_MEMORY_ERROR: i32 = 6;
+const EXIT_STEP_LIMIT: i32 = 7;
+const EXIT_TRACE_DIVERGED: i32 = 8;
+const EXIT_BREAKPOINT: i32 = 9;
 
 #[derive(Parser)]
-#[clap(version = "0.1", author = "kkinos", about = "ZKTC emulator")]
+#[clap(
+    version = "0.1",
+    author = "kkinos",
+    about = "ZKTC emulator",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// rom file path
-    rom_file_path: String,
+    #[arg(required_unless_present = "machine_file")]
+    rom_file_path: Option<String>,
 
     /// ram file path
     #[arg(long = "ram", default_value = "none")]
     ram_file_name: String,
+
+    /// serve the gdb remote protocol on this local port instead of the prompt
+    #[arg(long = "gdb")]
+    gdb_port: Option<u16>,
+
+    /// memory kept for stepping back, e.g. 64M, 0 turns it off
+    #[arg(long = "history", default_value = "64M", value_parser = parse_size)]
+    history_budget: usize,
+
+    /// start from a state written by the save command
+    #[arg(long = "load-state")]
+    load_state: Option<String>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+}
+
+#[derive(clap::Args)]
+struct MachineArgs {
+    /// TOML or JSON (.json) description of the memory map, the rom and ram files replace the
+    /// images of its first rom and ram regions
+    #[arg(long = "machine")]
+    machine_file: Option<String>,
+
+    /// also load an image, at the address after @ unless it is Intel HEX or S-records with
+    /// addresses of their own, e.g. data.bin@0x9000
+    #[arg(long = "load", value_parser = parse_load)]
+    loads: Vec<(String, Option<u16>)>,
+
+    /// symbol file with a label and an address per line, e.g. "pass 0xb020", for the commands,
+    /// traces and disassembly
+    #[arg(long = "symbols")]
+    symbols: Option<String>,
+
+    /// format of the rom, ram and loaded images, detected from their content by default
+    #[arg(long = "format", value_enum)]
+    format: Option<ImageFormat>,
+
+    /// add a uart sending to and receiving from stdio, a new pseudo-terminal (pty) or sending to
+    /// a file (file:PATH)
+    #[arg(long = "uart", value_parser = parse_uart)]
+    uart: Option<UartTarget>,
+
+    /// address of the uart registers
+    #[arg(long = "uart-base", default_value = "0x9000", value_parser = parse_hex)]
+    uart_base: u16,
+
+    /// instructions or cycles per count of tr, 0 stops the timer
+    #[arg(long = "timer-rate", default_value = "0")]
+    timer_rate: u32,
+
+    /// what the timer counts
+    #[arg(long = "timer-clock", value_enum, default_value = "instruction")]
+    timer_clock: TimerClock,
+
+    /// trap privileged instructions executed in user mode
+    #[arg(long = "strict")]
+    strict: bool,
+
+    /// trap user mode loads and stores to this region, e.g. 0x0000-0x0fff, implies --strict
+    #[arg(long = "protect", value_parser = parse_region)]
+    protect: Vec<Region>,
+
+    /// on an illegal instruction, bus error or misaligned access, stop or enter the trap handler
+    #[arg(long = "faults", value_enum, default_value = "stop")]
+    faults: Faults,
+
+    /// on a store to ROM or another region without the write permission, ignore it, ignore it
+    /// with a warning, stop or enter the trap handler with a bus error
+    #[arg(long = "rom-writes", value_enum, default_value = "stop")]
+    rom_writes: RomWrites,
+
+    /// word loads and stores at odd addresses fault, access the two bytes at the address (split)
+    /// or ignore the low bit of the address (align)
+    #[arg(long = "misaligned", value_enum, default_value = "fault")]
+    misaligned: Misaligned,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Faults {
+    Stop,
+    Exception,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImageFormat {
+    Mem,
+    Ihex,
+    Srec,
+    Bin,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Misaligned {
+    Fault,
+    Split,
+    Align,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RomWrites {
+    Ignore,
+    Warn,
+    Stop,
+    Exception,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TimerClock {
+    Instruction,
+    Cycle,
+}
+
+#[derive(Clone)]
+enum UartTarget {
+    Stdio,
+    Pty,
+    File(String),
 }
+
+#[derive(clap::Args)]
+struct TraceArgs {
+    /// print every executed instruction
+    #[arg(long = "trace")]
+    trace: bool,
+
+    /// trace format, implies --trace
+    #[arg(long = "trace-format", value_enum)]
+    trace_format: Option<TraceFormat>,
+
+    /// write the trace to this file instead of stdout, implies --trace
+    #[arg(long = "trace-file")]
+    trace_file: Option<String>,
+
+    /// stop at the first instruction that does not match this reference trace
+    #[arg(long = "compare-trace")]
+    compare_trace: Option<String>,
+
+    /// what each column of the reference trace holds, "_" skips a column
+    #[arg(long = "compare-columns", default_value = compare::DEFAULT_COLUMNS)]
+    compare_columns: String,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TraceFormat {
+    Text,
+    Jsonl,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// disassemble a mem file
+    Disasm {
+        /// mem file path, or any image format
+        mem_file_path: String,
+
+        /// address the mem file is loaded at
+        #[arg(short = 'b', long = "base", default_value = "0xb000", value_parser = parse_hex)]
+        base: u16,
+
+        /// image format, detected from the content by default
+        #[arg(long = "format", value_enum)]
+        format: Option<ImageFormat>,
+
+        /// symbol file naming the addresses
+        #[arg(long = "symbols")]
+        symbols: Option<String>,
+    },
+
+    /// assemble an asm file into a mem file
+    Asm {
+        /// asm file path
+        asm_file_path: String,
+
+        /// output mem file path
+        #[arg(short = 'o', long = "output", default_value = "a.mem")]
+        output: String,
+
+        /// address the program is placed at
+        #[arg(short = 'b', long = "base", default_value = "0xb000", value_parser = parse_hex)]
+        base: u16,
+
+        /// also write the labels to this symbol file
+        #[arg(short = 's', long = "symbols")]
+        symbols: Option<String>,
+    },
+
+    /// run without the prompt until the program stops and exit with a status telling why
+    Run {
+        /// rom file path
+        #[arg(required_unless_present = "machine_file")]
+        rom_file_path: Option<String>,
+
+        /// ram file path
+        #[arg(long = "ram", default_value = "none")]
+        ram_file_name: String,
+
+        /// stop after this many instructions
+        #[arg(long = "max-steps")]
+        max_steps: Option<u64>,
+
+        /// take the exit status from this memory cell (1 : pass, 2 : fail)
+        #[arg(long = "status", num_args = 0..=1, default_missing_value = "0xfffe", value_parser = parse_hex)]
+        status_address: Option<u16>,
+
+        /// start from a state written by the save command
+        #[arg(long = "load-state")]
+        load_state: Option<String>,
+
+        #[command(flatten)]
+        machine: Box<MachineArgs>,
+
+        #[command(flatten)]
+        trace: TraceArgs,
+    },
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let rom_file = load_mem_file(args.rom_file_path)?;
+    match args.command {
+        Some(Command::Disasm {
+            mem_file_path,
+            base,
+            format,
+            symbols,
+        }) => {
+            let image = load_image(&mem_file_path, format)?;
+            let symbols = load_symbols(symbols.as_deref())?;
+            for (address, bytes) in image.placed(base) {
+                for line in disasm::disassemble(bytes, address, &symbols) {
+                    println!("{}", line);
+                }
+            }
+            return Ok(());
+        }
+        Some(Command::Asm {
+            asm_file_path,
+            output,
+            base,
+            symbols,
+        }) => {
+            let src = std::fs::read_to_string(&asm_file_path)
+                .with_context(|| format!("could not read asm file '{}'", asm_file_path))?;
+            let (bytes, labels) = asm::assemble_with_symbols(&src, base)
+                .with_context(|| format!("could not assemble '{}'", asm_file_path))?;
+            std::fs::write(&output, loader::format_mem(&bytes))
+                .with_context(|| format!("could not write mem file '{}'", output))?;
+            if let Some(path) = symbols {
+                std::fs::write(&path, labels.to_string())
+                    .with_context(|| format!("could not write symbol file '{}'", path))?;
+            }
+            return Ok(());
+        }
+        Some(Command::Run {
+            rom_file_path,
+            ram_file_name,
+            max_steps,
+            status_address,
+            load_state,
+            machine,
+            trace,
+        }) => {
+            let board = load_machine(rom_file_path, ram_file_name, &machine)?;
+            let mut zktc = Zktc::from_machine(&board)?;
+            setup_machine(&mut zktc, &board, &machine, true)?;
+            if let Some(path) = load_state {
+                restore(&mut zktc, &path)?;
+            }
+            zktc.set_trace(trace_sink(&trace)?);
+            zktc.set_compare(comparator(&trace)?);
+            let code = run_batch(&mut zktc, max_steps, status_address);
+            // exit does not run destructors, flush the trace file first
+            drop(zktc);
+            std::process::exit(code);
+        }
+        None => {}
+    }
 
-    let ram_file = if args.ram_file_name.as_str() == "none" {
-        vec![]
-    } else {
-        load_mem_file(args.ram_file_name)?
-    };
+    let board = load_machine(args.rom_file_path, args.ram_file_name, &args.machine)?;
+    let mut zktc = Zktc::from_machine(&board)?;
+    // the prompt reads stdin, so the uart only receives from it when gdb is used instead
+    setup_machine(&mut zktc, &board, &args.machine, args.gdb_port.is_some())?;
+    if let Some(path) = &args.load_state {
+        restore(&mut zktc, path)?;
+    }
+    zktc.set_trace(trace_sink(&args.trace)?);
+    zktc.set_compare(comparator(&args.trace)?);
+    zktc.set_history_budget(args.history_budget);
 
-    let mut zktc = Zktc::new(rom_file, ram_file)?;
+    if let Some(port) = args.gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .with_context(|| format!("could not listen on port {}", port))?;
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+        gdb::serve(&mut zktc, listener)?;
+        return Ok(());
+    }
 
     let mut rl = DefaultEditor::new()?;
 
@@ -55,17 +374,264 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn load_mem_file(path: String) -> Result<Vec<u8>> {
-    let f = std::fs::read_to_string(path.clone())
-        .with_context(|| format!("could not read mem file '{}'", path))?;
-    let f = f.split_whitespace().collect::<Vec<_>>();
+fn trace_sink(args: &TraceArgs) -> Result<Option<Box<dyn TraceSink>>> {
+    if !args.trace && args.trace_format.is_none() && args.trace_file.is_none() {
+        return Ok(None);
+    }
+    let out: Box<dyn Write + Send> = match &args.trace_file {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("could not create trace file '{}'", path)
+            })?))
+        }
+        None => Box::new(io::stdout()),
+    };
+    Ok(Some(match args.trace_format.unwrap_or(TraceFormat::Text) {
+        TraceFormat::Text => Box::new(TextSink::new(out)),
+        TraceFormat::Jsonl => Box::new(JsonlSink::new(out)),
+    }))
+}
+
+fn comparator(args: &TraceArgs) -> Result<Option<Comparator>> {
+    let Some(path) = &args.compare_trace else {
+        return Ok(None);
+    };
+    let columns = Columns::parse(&args.compare_columns).context("invalid --compare-columns")?;
+    let src = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read reference trace '{}'", path))?;
+    let records = compare::parse_reference(&src, &columns)
+        .with_context(|| format!("could not parse reference trace '{}'", path))?;
+    Ok(Some(Comparator::new(records, columns)))
+}
+
+fn setup_machine(zktc: &mut Zktc, board: &Machine, args: &MachineArgs, stdin: bool) -> Result<()> {
+    let timer = zktc.timer_mut();
+    timer.rate = args.timer_rate;
+    timer.clock = match args.timer_clock {
+        TimerClock::Instruction => Clock::Instruction,
+        TimerClock::Cycle => Clock::Cycle,
+    };
+    zktc.set_fault_policy(match args.faults {
+        Faults::Stop => FaultPolicy::Stop,
+        Faults::Exception => FaultPolicy::Exception,
+    });
+    zktc.set_write_policy(match args.rom_writes {
+        RomWrites::Ignore => WritePolicy::Ignore,
+        RomWrites::Warn => WritePolicy::Warn,
+        RomWrites::Stop => WritePolicy::Stop,
+        RomWrites::Exception => WritePolicy::Exception,
+    });
+    zktc.memory_mut().set_alignment(match args.misaligned {
+        Misaligned::Fault => Alignment::Fault,
+        Misaligned::Split => Alignment::Split,
+        Misaligned::Align => Alignment::ForceAlign,
+    });
+    if args.strict || !args.protect.is_empty() {
+        zktc.set_protection(Some(Protection::new(args.protect.clone())));
+    }
+    zktc.set_symbols(load_symbols(args.symbols.as_deref())?);
+    for (path, address) in &args.loads {
+        let image = load_image(path, args.format)?;
+        let base = match (address, image.absolute) {
+            (Some(address), _) => *address,
+            (None, true) => 0,
+            (None, false) => anyhow::bail!("'{}' needs a load address, e.g. {}@0x9000", path, path),
+        };
+        zktc.memory_mut()
+            .write_image(&image, base)
+            .with_context(|| format!("could not load image '{}'", path))?;
+    }
+
+    // a uart region of the machine description takes the place of --uart-base
+    let mut uarts = board
+        .regions
+        .iter()
+        .filter(|r| r.kind == RegionKind::Device(DeviceKind::Uart));
+    let (name, base, line, permissions) = match (uarts.next(), &args.uart) {
+        (Some(region), _) => (
+            region.name.as_str(),
+            region.base,
+            region.irq.unwrap_or(intc::UART_LINE),
+            Some(region.permissions),
+        ),
+        (None, Some(_)) => ("uart", args.uart_base, intc::UART_LINE, None),
+        (None, None) => return Ok(()),
+    };
+    if uarts.next().is_some() {
+        anyhow::bail!("only one uart is supported");
+    }
+    let device = match args.uart.as_ref().unwrap_or(&UartTarget::Stdio) {
+        UartTarget::Stdio => Uart::stdio(stdin),
+        #[cfg(unix)]
+        UartTarget::Pty => {
+            let (device, path) = Uart::pty().context("could not open a pseudo-terminal")?;
+            println!("uart connected to {}", path);
+            device
+        }
+        #[cfg(not(unix))]
+        UartTarget::Pty => anyhow::bail!("pseudo-terminals are only supported on unix"),
+        UartTarget::File(path) => {
+            Uart::file(path).with_context(|| format!("could not create uart file '{}'", path))?
+        }
+    };
+    let memory = zktc.memory_mut();
+    memory
+        .map(name, base, uart::SIZE, Box::new(device))
+        .context("could not map the uart")?;
+    if let Some(permissions) = permissions {
+        memory.set_permissions(name, permissions)?;
+    }
+    memory.connect_irq(name, line)?;
+    Ok(())
+}
+
+fn restore(zktc: &mut Zktc, path: &str) -> Result<()> {
+    let bytes = std::fs::read(path).with_context(|| format!("could not read state '{}'", path))?;
+    let snapshot =
+        Snapshot::decode(&bytes).with_context(|| format!("could not load state '{}'", path))?;
+    zktc.restore(snapshot)
+        .with_context(|| format!("could not load state '{}'", path))
+}
+
+fn load_machine(
+    rom_file_path: Option<String>,
+    ram_file_name: String,
+    args: &MachineArgs,
+) -> Result<Machine> {
+    let mut board = match &args.machine_file {
+        Some(path) => Machine::load(path)
+            .with_context(|| format!("could not load machine description '{}'", path))?,
+        None => Machine::default(),
+    };
+    if let Some(path) = rom_file_path {
+        board.set_image(RegionKind::Rom, &load_image(&path, args.format)?)?;
+    }
+    if ram_file_name.as_str() != "none" {
+        board.set_image(RegionKind::Ram, &load_image(&ram_file_name, args.format)?)?;
+    }
+    Ok(board)
+}
+
+fn load_image(path: &str, format: Option<ImageFormat>) -> Result<Image> {
+    let format = format.map(|format| match format {
+        ImageFormat::Mem => Format::Mem,
+        ImageFormat::Ihex => Format::IntelHex,
+        ImageFormat::Srec => Format::SRecord,
+        ImageFormat::Bin => Format::Binary,
+    });
+    loader::load_image(path, format).with_context(|| format!("could not load image '{}'", path))
+}
+
+fn load_symbols(path: Option<&str>) -> Result<Symbols> {
+    match path {
+        Some(path) => {
+            Symbols::load(path).with_context(|| format!("could not load symbols '{}'", path))
+        }
+        None => Ok(Symbols::default()),
+    }
+}
+
+fn run_batch(zktc: &mut Zktc, max_steps: Option<u64>, status_address: Option<u16>) -> i32 {
+    let reason = match max_steps {
+        Some(max_steps) => zktc.run_steps(max_steps),
+        None => Some(zktc.run()),
+    };
+    let pc = zktc.cpu().pc;
+    let steps = zktc.steps();
+
+    match reason {
+        Some(StopReason::Error(Error::DebugInterrupt())) => {
+            eprintln!("debug interrupt at 0x{:04x} after {} steps", pc, steps);
+            if let Some(remaining) = zktc.compare().map(|c| c.remaining()).filter(|&n| n > 0) {
+                eprintln!(
+                    "trace diverged : the reference has {} more records",
+                    remaining
+                );
+                return EXIT_TRACE_DIVERGED;
+            }
+            let Some(address) = status_address else {
+                return EXIT_OK;
+            };
+            match zktc.read_memory(address, false) {
+                Ok(1) => {
+                    eprintln!("pass");
+                    EXIT_OK
+                }
+                Ok(2) => {
+                    eprintln!("fail");
+                    EXIT_TEST_FAILED
+                }
+                Ok(data) => {
+                    eprintln!("no verdict in 0x{:04x} : 0x{:04x}", address, data);
+                    EXIT_NO_VERDICT
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    EXIT_MEMORY_ERROR
+                }
+            }
+        }
+        Some(StopReason::Error(e @ Error::UnknownInstruction(_))) => {
+            eprintln!("{} at 0x{:04x} after {} steps", e, pc, steps);
+            EXIT_UNKNOWN_INSTRUCTION
+        }
+        Some(StopReason::Error(e)) => {
+            eprintln!("{} at 0x{:04x} after {} steps", e, pc, steps);
+            EXIT_MEMORY_ERROR
+        }
+        Some(StopReason::Divergence(divergence)) => {
+            eprintln!("{}", divergence);
+            EXIT_TRACE_DIVERGED
+        }
+        Some(StopReason::Breakpoint(id)) => {
+            eprintln!("breakpoint {} at 0x{:04x} after {} steps", id, pc, steps);
+            EXIT_BREAKPOINT
+        }
+        Some(StopReason::Watchpoint(hit)) => {
+            eprintln!(
+                "watchpoint {} : access to 0x{:04x} at 0x{:04x} after {} steps",
+                hit.id, hit.address, hit.pc, steps
+            );
+            EXIT_BREAKPOINT
+        }
+        None => {
+            eprintln!("step limit reached at 0x{:04x} after {} steps", pc, steps);
+            EXIT_STEP_LIMIT
+        }
+    }
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    history::parse_size(s)
+        .ok_or_else(|| "size is a number of bytes with an optional k or M".to_string())
+}
+
+// FILE or FILE@ADDRESS
+fn parse_load(s: &str) -> Result<(String, Option<u16>), String> {
+    match s.rsplit_once('@') {
+        Some((path, address)) => Ok((path.to_string(), Some(parse_hex(address)?))),
+        None => Ok((s.to_string(), None)),
+    }
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    Region::parse(s).ok_or_else(|| "expected a hexadecimal range like 0x0000-0x0fff".to_string())
+}
 
-    let mut bytes: Vec<u8> = vec![];
-    for line in f {
-        let mut hex =
-            hex::decode(line).with_context(|| format!("could not decode '{}' to hex", line))?;
-        bytes.append(&mut hex);
+fn parse_uart(s: &str) -> Result<UartTarget, String> {
+    match s {
+        "stdio" => Ok(UartTarget::Stdio),
+        "pty" => Ok(UartTarget::Pty),
+        _ => match s.strip_prefix("file:") {
+            Some(path) if !path.is_empty() => Ok(UartTarget::File(path.to_string())),
+            _ => Err("expected stdio, pty or file:PATH".to_string()),
+        },
     }
+}
 
-    Ok(bytes)
+fn parse_hex(s: &str) -> Result<u16, String> {
+    let hex = s
+        .strip_prefix("0x")
+        .ok_or_else(|| "address is only hexadecimal".to_string())?;
+    u16::from_str_radix(hex, 16).map_err(|e| e.to_string())
 }
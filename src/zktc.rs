@@ -1,50 +1,108 @@
-mod cpu;
-mod memory;
-use cpu::Cpu;
-use memory::Memory;
+pub mod asm;
+pub mod breakpoint;
+pub mod bus;
+pub mod compare;
+pub mod cpu;
+pub mod disasm;
+pub mod expr;
+pub mod gdb;
+pub mod history;
+pub mod inst;
+pub mod intc;
+pub mod loader;
+pub mod machine;
+pub mod memory;
+pub mod privilege;
+pub mod snapshot;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+pub mod uart;
+use breakpoint::{BreakpointKind, Breakpoints, Condition, WatchHit, WatchKind};
+use compare::{Comparator, Divergence};
+use cpu::{Cpu, Exception};
+use history::{History, UndoEntry};
+use inst::{decode, DecodeError, Instruction};
+use machine::Machine;
+use memory::{Memory, MemoryAccess, MemoryError};
+use privilege::Protection;
+use snapshot::{Snapshot, SnapshotError};
+use std::fmt;
+use symbols::Symbols;
+use timer::Timer;
+use trace::{MemoryRecord, RegWrite, TextSink, TraceEvent, TraceSink};
 
-#[derive(Debug)]
 pub struct Zktc {
     cpu: Cpu,
     memory: Memory,
-    break_point: Option<u16>,
+    breakpoints: Breakpoints,
+    watch_hit: Option<WatchHit>,
+    steps: u64,
+    trace: Option<Box<dyn TraceSink>>,
+    compare: Option<Comparator>,
+    divergence: Option<Divergence>,
+    history: History,
+    timer: Timer,
+    protection: Option<Protection>,
+    fault_policy: FaultPolicy,
+    write_policy: WritePolicy,
+    reset_pc: u16,
+    symbols: Symbols,
 }
 
-pub enum InstInfo {
-    R {
-        mnemonic: String,
-        rd: u8,
-        rs: u8,
-    },
-    I5 {
-        mnemonic: String,
-        rd: u8,
-        rs: u8,
-        imm: Option<u16>,
-        imm_sext: Option<i16>,
-    },
-    I8 {
-        mnemonic: String,
-        rd: u8,
-        rs: u8,
-        imm: Option<u16>,
-        imm_sext: Option<i16>,
-    },
-    C1 {
-        mnemonic: String,
-        rd: u8,
-    },
-    C2 {
-        mnemonic: String,
-    },
-    Trap {
-        mnemonic: String,
-    },
+// What happens when an instruction cannot be fetched, decoded or access memory.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FaultPolicy {
+    // stop with the error
+    #[default]
+    Stop,
+    // enter the trap handler with ppc at the faulting instruction
+    Exception,
+}
+
+// What happens when a store hits a region without the write permission, such as ROM. Writes
+// from the debugger are always made.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WritePolicy {
+    // drop the write
+    Ignore,
+    // drop the write and print a warning
+    Warn,
+    // stop with the error
+    #[default]
+    Stop,
+    // enter the trap handler with a bus error
+    Exception,
+}
+
+impl fmt::Debug for Zktc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Zktc")
+            .field("cpu", &self.cpu)
+            .field("breakpoints", &self.breakpoints)
+            .field("steps", &self.steps)
+            .field("trace", &self.trace.is_some())
+            .field("compare", &self.compare)
+            .field("history", &self.history)
+            .field("timer", &self.timer)
+            .field("protection", &self.protection)
+            .field("fault_policy", &self.fault_policy)
+            .field("write_policy", &self.write_policy)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StopReason {
+    Breakpoint(usize),
+    Watchpoint(WatchHit),
+    Divergence(Divergence),
+    Error(Error),
 }
 
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum Error {
-    #[error("memory error")]
+    #[error("memory error : {0}")]
     MemoryError(#[from] memory::MemoryError),
 
     #[error("unknown instruction 0x{0:04x}")]
@@ -55,47 +113,554 @@ pub enum Error {
 
     #[error("exit")]
     EmulatorExit(),
+
+    #[error("no more history")]
+    NoHistory(),
+
+    #[error(transparent)]
+    SnapshotError(#[from] SnapshotError),
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::UnknownInstruction(word) => Error::UnknownInstruction(word),
+        }
+    }
 }
 
 impl Zktc {
     pub fn new(rom_file: Vec<u8>, ram_file: Vec<u8>) -> Result<Self, Error> {
-        Ok(Zktc {
-            cpu: Cpu::new(),
-            memory: Memory::new(rom_file, ram_file)?,
-            break_point: None,
-        })
+        Ok(Self::with_memory(
+            Memory::new(rom_file, ram_file)?,
+            memory::ROM_LOW_ADDRESS,
+        ))
+    }
+
+    // Uart regions of the machine are not mapped, see Memory::from_machine.
+    pub fn from_machine(machine: &Machine) -> Result<Self, Error> {
+        let mut zktc = Self::with_memory(Memory::from_machine(machine)?, machine.reset_pc);
+        zktc.cpu.psr_layout = machine.psr;
+        Ok(zktc)
+    }
+
+    fn with_memory(memory: Memory, reset_pc: u16) -> Self {
+        Zktc {
+            cpu: Cpu::with_reset_pc(reset_pc),
+            memory,
+            breakpoints: Breakpoints::new(),
+            watch_hit: None,
+            steps: 0,
+            trace: None,
+            compare: None,
+            divergence: None,
+            history: History::default(),
+            timer: Timer::default(),
+            protection: None,
+            fault_policy: FaultPolicy::default(),
+            write_policy: WritePolicy::default(),
+            reset_pc,
+            symbols: Symbols::default(),
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn read_gr(&self, num: u8) -> u16 {
+        self.cpu.get_gr(num)
+    }
+
+    pub fn write_gr(&mut self, num: u8, data: u16) {
+        self.cpu.set_gr(num, data);
+    }
+
+    pub fn read_memory(&self, address: u16, half: bool) -> Result<u16, Error> {
+        Ok(self.memory.read_from_memory(&address, half)?)
+    }
+
+    pub fn write_memory(&mut self, address: u16, data: u16, half: bool) -> Result<(), Error> {
+        Ok(self.memory.write_to_memory(&address, data, half)?)
+    }
+
+    // number of instructions retired so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn set_trace(&mut self, sink: Option<Box<dyn TraceSink>>) {
+        self.trace = sink;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    // Checks every retired instruction against a reference trace, run stops at the first mismatch.
+    pub fn set_compare(&mut self, comparator: Option<Comparator>) {
+        self.compare = comparator;
+    }
+
+    pub fn compare(&self) -> Option<&Comparator> {
+        self.compare.as_ref()
+    }
+
+    pub fn timer(&self) -> &Timer {
+        &self.timer
+    }
+
+    pub fn timer_mut(&mut self) -> &mut Timer {
+        &mut self.timer
+    }
+
+    // Turns strict mode on, or off with None.
+    pub fn set_protection(&mut self, protection: Option<Protection>) {
+        self.protection = protection;
+    }
+
+    pub fn protection(&self) -> Option<&Protection> {
+        self.protection.as_ref()
+    }
+
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy) {
+        self.fault_policy = policy;
+    }
+
+    pub fn fault_policy(&self) -> FaultPolicy {
+        self.fault_policy
+    }
+
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+        self.memory.set_discard_read_only_writes(matches!(
+            policy,
+            WritePolicy::Ignore | WritePolicy::Warn
+        ));
+    }
+
+    pub fn write_policy(&self) -> WritePolicy {
+        self.write_policy
+    }
+
+    // Labels accepted in place of addresses by the commands and shown in traces and disassembly.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Raises a line of the interrupt controller, as an external device would.
+    pub fn raise_irq(&mut self, line: u8) {
+        self.memory.raise_irq(1 << line);
+    }
+
+    // Resets the cpu and the devices, memory contents are kept.
+    pub fn reset(&mut self) {
+        self.cpu = Cpu {
+            psr_layout: self.cpu.psr_layout,
+            ..Cpu::with_reset_pc(self.reset_pc)
+        };
+        self.memory.reset();
+        self.timer.reset();
+        self.steps = 0;
+        self.watch_hit = None;
+        self.divergence = None;
+        self.history.clear();
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cpu: self.cpu.clone(),
+            devices: self
+                .memory
+                .bus()
+                .mappings()
+                .map(|m| (m.name.clone(), m.device().save_state()))
+                .collect(),
+            breakpoints: self.breakpoints.iter().cloned().collect(),
+            steps: self.steps,
+            timer: self.timer.state,
+        }
+    }
+
+    // The undo log is cleared, stepping back cannot go past the restored state.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        if let Some((name, _)) = snapshot
+            .devices
+            .iter()
+            .find(|(name, _)| self.memory.device(name).is_none())
+        {
+            Err(SnapshotError::UnknownDevice(name.clone()))?
+        }
+        // a state is only checked by loading it, the devices loaded before a bad one are put
+        // back so that the machine is left as it was
+        let mut loaded: Vec<(&String, Vec<u8>)> = vec![];
+        for (name, state) in &snapshot.devices {
+            let device = self.memory.device_mut(name).unwrap();
+            let previous = device.save_state();
+            if let Err(e) = device.load_state(state) {
+                for (name, previous) in loaded.drain(..) {
+                    let _ = self.memory.device_mut(name).unwrap().load_state(&previous);
+                }
+                Err(SnapshotError::InvalidDeviceState(name.clone(), e))?
+            }
+            loaded.push((name, previous));
+        }
+        // the layout belongs to the machine, not to the snapshot
+        self.cpu = Cpu {
+            psr_layout: self.cpu.psr_layout,
+            ..snapshot.cpu
+        };
+        self.timer.state = snapshot.timer;
+        self.breakpoints.restore(snapshot.breakpoints);
+        self.steps = snapshot.steps;
+        self.watch_hit = None;
+        self.divergence = None;
+        self.history.clear();
+        Ok(())
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    // Bytes kept for the undo log used by step_back, 0 turns it off.
+    pub fn set_history_budget(&mut self, budget: usize) {
+        self.history.set_budget(budget);
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
     }
 
     pub fn do_cmd(&mut self, cmd: Vec<&str>) -> Result<(), Error> {
         match cmd[0] {
-            "run" | "r" => self.run(),
+            "run" | "r" => match self.run() {
+                StopReason::Breakpoint(id) => {
+                    println!("breakpoint {} at {}", id, self.describe(self.cpu.pc));
+                }
+                StopReason::Watchpoint(hit) => Self::print_watch_hit(&hit),
+                StopReason::Divergence(divergence) => println!("{}", divergence),
+                StopReason::Error(e) => {
+                    eprintln!("{}", e);
+                }
+            },
             "step" | "s" => {
+                let pc = self.cpu.pc;
+                let steps = self.steps;
                 if let Err(e) = self.step() {
                     eprintln!("{}", e);
                 }
+                // show what was executed even when tracing is off
+                if !self.is_tracing() && self.steps != steps {
+                    self.print_inst_at(steps, pc);
+                }
+                if let Some(divergence) = self.divergence.take() {
+                    println!("{}", divergence);
+                }
+                if let Some(hit) = self.watch_hit.take() {
+                    Self::print_watch_hit(&hit);
+                }
+            }
+            "rstep" | "rs" => match self.step_back() {
+                Ok(()) => self.print_inst_at(self.steps, self.cpu.pc),
+                Err(e) => eprintln!("{}", e),
+            },
+            "rcontinue" | "rc" => match self.run_back() {
+                StopReason::Breakpoint(id) => {
+                    println!("breakpoint {} at {}", id, self.describe(self.cpu.pc));
+                }
+                StopReason::Watchpoint(hit) => Self::print_watch_hit(&hit),
+                StopReason::Divergence(divergence) => println!("{}", divergence),
+                StopReason::Error(e) => {
+                    eprintln!("{} at step {}", e, self.steps);
+                }
+            },
+            "goto" => {
+                let Some(Ok(step)) = cmd.get(1).map(|n| n.parse::<u64>()) else {
+                    eprintln!("invalid command\ne.g. : goto 100");
+                    return Ok(());
+                };
+                if let Err(e) = self.goto(step) {
+                    eprintln!("{}", e);
+                }
+                println!("step {} at 0x{:04x}", self.steps, self.cpu.pc);
+            }
+            "history" => {
+                if let Some(size) = cmd.get(1) {
+                    let Some(budget) = history::parse_size(size) else {
+                        eprintln!("invalid command\ne.g. : history 64M");
+                        return Ok(());
+                    };
+                    self.set_history_budget(budget);
+                }
+                let oldest = self.steps.saturating_sub(self.history.len() as u64);
+                println!(
+                    "{} steps recorded (from step {}), {} of {} bytes",
+                    self.history.len(),
+                    oldest,
+                    self.history.usage(),
+                    self.history.budget()
+                );
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let example = format!("{} 0x8000 2", cmd[0]);
+                if cmd.len() != 2 && cmd.len() != 3 {
+                    eprintln!("invalid command\ne.g. : {}", example);
+                    return Ok(());
+                }
+
+                let Some(addr) = self.parse_address(cmd[1], &example) else {
+                    return Ok(());
+                };
+                let len = match cmd.get(2).map(|len| len.parse::<u16>()) {
+                    None => 2,
+                    Some(Ok(len)) if len > 0 => len,
+                    Some(_) => {
+                        eprintln!("invalid length\ne.g. : {}", example);
+                        return Ok(());
+                    }
+                };
+                let kind = match cmd[0] {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let id = self.breakpoints.add_watch(kind, addr, len);
+                println!(
+                    "watchpoint {} at 0x{:04x}-0x{:04x}",
+                    id,
+                    addr,
+                    addr as u32 + len as u32 - 1
+                );
             }
             "exit" => {
                 println!("exit");
                 return Err(Error::EmulatorExit());
             }
             "break" | "b" => {
-                if cmd.len() != 2 {
-                    eprintln!("invalid command\ne.g. : b 0x8000");
+                if cmd.len() != 2 && (cmd.len() < 4 || cmd[2] != "if") {
+                    eprintln!("invalid command\ne.g. : b 0x8000\ne.g. : b 0x8000 if x1 == 0x10");
                     return Ok(());
                 }
 
-                let addr = cmd[1];
-                if !addr.starts_with("0x") {
-                    eprintln!("address is only hexadecimal\ne.g. : b 0x8000");
+                if let Some(addr) = self.parse_address(cmd[1], "b 0x8000") {
+                    let condition = if cmd.len() > 2 {
+                        match Condition::parse(&cmd[3..].join(" ")) {
+                            Ok(condition) => Some(condition),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                return Ok(());
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    let id = self.set_break(addr);
+                    println!("breakpoint {} at {}", id, self.describe(addr));
+                    self.breakpoints.set_condition(id, condition).unwrap();
+                }
+            }
+            "condition" => {
+                if cmd.len() < 2 {
+                    eprintln!("invalid command\ne.g. : condition 1 x1 == 0x10");
                     return Ok(());
                 }
-                match u16::from_str_radix(addr.trim_start_matches("0x"), 16) {
-                    Ok(addr) => self.set_break(addr),
-                    Err(_) => {
-                        eprintln!("invalid address\ne.g. : b 0x8000");
+                let Ok(id) = cmd[1].parse::<usize>() else {
+                    eprintln!("invalid breakpoint number\ne.g. : condition 1 x1 == 0x10");
+                    return Ok(());
+                };
+                let condition = if cmd.len() > 2 {
+                    match Condition::parse(&cmd[2..].join(" ")) {
+                        Ok(condition) => Some(condition),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            return Ok(());
+                        }
+                    }
+                } else {
+                    None
+                };
+                if let Err(e) = self.breakpoints.set_condition(id, condition) {
+                    eprintln!("{}", e);
+                }
+            }
+            "print" | "p" => {
+                if cmd.len() < 2 {
+                    eprintln!("invalid command\ne.g. : p [sp] + x1");
+                    return Ok(());
+                }
+                match self.eval(&cmd[1..].join(" ")) {
+                    Ok(value) => println!("0x{:04x} ({})", value, value),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            "delete" | "del" => {
+                if cmd.len() == 1 {
+                    self.breakpoints.clear();
+                    return Ok(());
+                }
+                for id in &cmd[1..] {
+                    match id.parse::<usize>() {
+                        Ok(id) => {
+                            if let Err(e) = self.breakpoints.delete(id) {
+                                eprintln!("{}", e);
+                            }
+                        }
+                        Err(_) => {
+                            eprintln!("invalid breakpoint number\ne.g. : delete 1");
+                        }
+                    }
+                }
+            }
+            "disable" | "enable" => {
+                if cmd.len() < 2 {
+                    eprintln!("invalid command\ne.g. : {} 1", cmd[0]);
+                    return Ok(());
+                }
+                for id in &cmd[1..] {
+                    match id.parse::<usize>() {
+                        Ok(id) => {
+                            if let Err(e) = self.breakpoints.set_enabled(id, cmd[0] == "enable") {
+                                eprintln!("{}", e);
+                            }
+                        }
+                        Err(_) => {
+                            eprintln!("invalid breakpoint number\ne.g. : {} 1", cmd[0]);
+                        }
+                    }
+                }
+            }
+            "ignore" => {
+                if cmd.len() != 3 {
+                    eprintln!("invalid command\ne.g. : ignore 1 10");
+                    return Ok(());
+                }
+                match (cmd[1].parse::<usize>(), cmd[2].parse::<u32>()) {
+                    (Ok(id), Ok(count)) => {
+                        if let Err(e) = self.breakpoints.set_ignore_count(id, count) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    _ => {
+                        eprintln!("invalid command\ne.g. : ignore 1 10");
+                    }
+                }
+            }
+            "info" => match cmd.get(1) {
+                Some(&"breakpoints") | Some(&"break") | Some(&"b") => self.print_breakpoints(),
+                _ => {
+                    eprintln!("invalid command\ne.g. : info breakpoints");
+                }
+            },
+            "trace" => match cmd.get(1) {
+                Some(&"on") => self.set_trace(Some(Box::new(TextSink::stdout()))),
+                Some(&"off") => self.set_trace(None),
+                None => println!("trace is {}", if self.is_tracing() { "on" } else { "off" }),
+                _ => {
+                    eprintln!("invalid command\ne.g. : trace on");
+                }
+            },
+            "save" | "load" => {
+                let example = format!("{} state.zsnap", cmd[0]);
+                if cmd.len() != 2 {
+                    eprintln!("invalid command\ne.g. : {}", example);
+                    return Ok(());
+                }
+                let result = if cmd[0] == "save" {
+                    std::fs::write(cmd[1], self.snapshot().encode()).map_err(|e| e.to_string())
+                } else {
+                    self.load_state_file(cmd[1])
+                };
+                match result {
+                    Ok(()) => println!("{} {} at step {}", cmd[0], cmd[1], self.steps),
+                    Err(e) => eprintln!("could not {} '{}' : {}", cmd[0], cmd[1], e),
+                }
+            }
+            "reset" => {
+                self.reset();
+                println!("reset, pc 0x{:04x}", self.cpu.pc);
+            }
+            "devices" => {
+                for m in self.memory.bus().mappings() {
+                    println!(
+                        "0x{:04x}-0x{:04x} {} {}",
+                        m.base,
+                        m.end(),
+                        m.permissions,
+                        m.name
+                    );
+                }
+            }
+            "timer" => {
+                if let Some(rate) = cmd.get(1) {
+                    let Ok(rate) = rate.parse::<u32>() else {
+                        eprintln!("invalid command\ne.g. : timer 100 cycle");
+                        return Ok(());
+                    };
+                    if let Some(clock) = cmd.get(2) {
+                        match clock.parse() {
+                            Ok(clock) => self.timer.clock = clock,
+                            Err(e) => {
+                                eprintln!("{}\ne.g. : timer 100 cycle", e);
+                                return Ok(());
+                            }
+                        }
                     }
+                    self.timer.rate = rate;
+                }
+                match self.timer.rate {
+                    0 => println!("tr 0x{:08x}, stopped", self.cpu.tr),
+                    rate => println!(
+                        "tr 0x{:08x}, advances every {} {}s",
+                        self.cpu.tr, rate, self.timer.clock
+                    ),
                 }
             }
+            "irq" => {
+                if let Some(line) = cmd.get(1) {
+                    match line.parse::<u8>() {
+                        Ok(line) if line < intc::LINES => self.raise_irq(line),
+                        _ => {
+                            eprintln!("invalid command\ne.g. : irq 3");
+                            return Ok(());
+                        }
+                    }
+                }
+                let Some(intc) = self.memory.device("intc") else {
+                    eprintln!("no interrupt controller");
+                    return Ok(());
+                };
+                println!(
+                    "pending 0x{:04x}, mask 0x{:04x}, interrupts {}",
+                    intc.peek16(intc::PENDING),
+                    intc.peek16(intc::MASK),
+                    if self.cpu.interrupts_enabled() {
+                        "enabled"
+                    } else {
+                        "disabled"
+                    }
+                );
+            }
             "regsters" | "regs" => self.print_regs(),
             "mem" | "m" => {
                 if cmd.len() != 3 {
@@ -103,55 +668,158 @@ impl Zktc {
                     return Ok(());
                 }
 
-                let addr = cmd[1];
-                let num = cmd[2];
-                if !addr.starts_with("0x") {
-                    eprintln!("address is only hexadecimal\ne.g. : m 0x8000 10");
+                let Some(addr) = self.parse_address(cmd[1], "m 0x8000 10") else {
+                    return Ok(());
+                };
+                if addr % 2 != 0 {
+                    eprintln!("address is only even\ne.g. : m 0x8000 10");
+                    return Ok(());
+                }
+                match cmd[2].parse::<u16>() {
+                    Ok(num) => {
+                        for i in 0..num {
+                            let address = addr.wrapping_add(i.wrapping_mul(2));
+                            match self.memory.read_from_memory(&address, false) {
+                                Ok(data) => {
+                                    println!(
+                                        "address : 0x{:04x} {:08b}\naddress : 0x{:04x} {:08b}",
+                                        address,
+                                        (data & 0x00ff) as u8,
+                                        address.wrapping_add(1),
+                                        ((data & 0xff00) >> 8) as u8,
+                                    );
+                                }
+                                Err(e) => {
+                                    eprintln!("{}", e);
+                                }
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        eprintln!("invalid command\ne.g. : m 0x8000 10");
+                    }
+                }
+            }
+            "disasm" | "d" => {
+                if cmd.len() != 3 {
+                    eprintln!("invalid command\ne.g. : d 0xb000 20");
+                    return Ok(());
+                }
+
+                let Some(addr) = self.parse_address(cmd[1], "d 0xb000 20") else {
+                    return Ok(());
+                };
+                if addr % 2 != 0 {
+                    eprintln!("address is only even\ne.g. : d 0xb000 20");
                     return Ok(());
                 }
-                match u16::from_str_radix(addr.trim_start_matches("0x"), 16) {
-                    Ok(addr) => {
-                        if addr % 2 == 0 {
-                            match num.parse::<u16>() {
-                                Ok(num) => {
-                                    for i in 0..num {
-                                        match self.memory.read_from_memory(&(addr + i * 2), false) {
-                                            Ok(data) => {
-                                                println!(
-                                                    "address : 0x{:04x} {:08b}\naddress : 0x{:04x} {:08b}",
-                                                    addr + (i * 2),
-                                                    (data & 0x00ff) as u8,
-                                                    addr + (i * 2) + 1,
-                                                    ((data & 0xff00) >> 8) as u8,
-                                                );
-                                            }
-                                            Err(e) => {
-                                                eprintln!("{}", e);
-                                            }
-                                        }
-                                    }
+                match cmd[2].parse::<u16>() {
+                    Ok(num) => {
+                        for i in 0..num {
+                            let addr = addr.wrapping_add(i * 2);
+                            match self.memory.read_from_memory(&addr, false) {
+                                Ok(word) => {
+                                    println!("{}", disasm::format_line(addr, word, &self.symbols))
                                 }
-                                Err(_) => {
-                                    eprintln!("invalid command\ne.g. : m 0x8000 10");
+                                Err(e) => {
+                                    eprintln!("{}", e);
+                                    break;
                                 }
                             }
-                        } else {
-                            eprintln!("address is only even\ne.g. : m 0x8000 10");
                         }
                     }
                     Err(_) => {
-                        eprintln!("invalid address\ne.g. : m 0x8000 10");
+                        eprintln!("invalid command\ne.g. : d 0xb000 20");
                     }
                 }
             }
+            "asm" => {
+                if cmd.len() < 3 {
+                    eprintln!("invalid command\ne.g. : asm 0xb000 addi x1, x0, 1");
+                    return Ok(());
+                }
+
+                let Some(addr) = self.parse_address(cmd[1], "asm 0xb000 addi x1, x0, 1") else {
+                    return Ok(());
+                };
+                if addr % 2 != 0 {
+                    eprintln!("address is only even\ne.g. : asm 0xb000 addi x1, x0, 1");
+                    return Ok(());
+                }
+                match asm::assemble_line(&cmd[2..].join(" ")) {
+                    Ok(word) => match self.memory.write_to_memory(&addr, word, false) {
+                        Ok(()) => println!("{}", disasm::format_line(addr, word, &self.symbols)),
+                        Err(e) => eprintln!("{}", e),
+                    },
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
             "help" => {
                 println!("run, r        : continue to execute until break point");
                 println!();
                 println!("step, s       : step execute");
                 println!();
-                println!("breakpoint, b : set breakpoint (b 0x8000)");
+                println!("rstep, rs     : step back one instruction");
+                println!();
+                println!("rcontinue, rc : run backwards to the previous breakpoint or watchpoint");
+                println!();
+                println!("goto          : go to a step number, backwards or forwards (goto 100)");
+                println!();
+                println!(
+                    "history       : show or set the memory used for stepping back (history 64M)"
+                );
+                println!();
+                println!(
+                    "breakpoint, b : set breakpoint (b 0x8000, b pass, b 0x8000 if x1 == 0x10 && [sp] < 4)"
+                );
+                println!();
+                println!(
+                    "condition     : change the condition of a breakpoint (condition 1 x1 == 0)"
+                );
+                println!();
+                println!("print, p      : evaluate an expression (p [x1 + 2] & 0xff)");
+                println!();
+                println!("watch         : stop when memory is written (watch 0x8000 2)");
+                println!();
+                println!("rwatch        : stop when memory is read (rwatch 0x8000 2)");
+                println!();
+                println!("awatch        : stop when memory is read or written (awatch 0x8000 2)");
                 println!();
-                println!("mem, m        : display data in memory (m 0x8000 10)");
+                println!(
+                    "delete, del   : delete breakpoints, all if no number is given (delete 1)"
+                );
+                println!();
+                println!("disable       : disable breakpoints (disable 1)");
+                println!();
+                println!("enable        : enable breakpoints (enable 1)");
+                println!();
+                println!("ignore        : ignore the next hits of a breakpoint (ignore 1 10)");
+                println!();
+                println!("info          : show breakpoints (info breakpoints)");
+                println!();
+                println!("mem, m        : display data in memory (m 0x8000 10, m expect 1)");
+                println!();
+                println!("disasm, d     : disassemble memory (d 0xb000 20, d fail+0x4 3)");
+                println!();
+                println!("asm           : assemble an instruction into memory (asm 0xb000 addi x1, x0, 1)");
+                println!();
+                println!("reset         : reset the cpu and the devices");
+                println!();
+                println!("devices       : show the devices on the bus and their permissions");
+                println!();
+                println!(
+                    "save          : save the whole machine state to a file (save state.zsnap)"
+                );
+                println!();
+                println!("load          : restore a state saved by save (load state.zsnap)");
+                println!();
+                println!("trace         : show each executed instruction (trace on, trace off)");
+                println!();
+                println!(
+                    "timer         : show or set how often tr advances, 0 stops it (timer 100 cycle)"
+                );
+                println!();
+                println!("irq           : show or raise interrupt lines (irq 3)");
                 println!();
                 println!("regsters, regs: display data in register");
                 println!();
@@ -167,534 +835,396 @@ impl Zktc {
         Ok(())
     }
 
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> StopReason {
         loop {
-            if let Err(e) = self.step() {
-                eprintln!("{}", e);
-                break;
-            }
-            if let Some(b) = self.break_point {
-                if self.cpu.pc == b {
-                    break;
-                }
+            if let Some(reason) = self.run_steps(u64::MAX) {
+                return reason;
             }
         }
     }
 
-    pub fn step(&mut self) -> Result<(), Error> {
-        let current_pc = self.cpu.pc;
-
-        let word = self.memory.read_from_memory(&current_pc, false)?;
-        if word == 0x0 {
-            return Err(Error::DebugInterrupt());
-        }
-        self.cpu.pc += 2;
-
-        let opcode = word & 0x001F;
-        let rd = ((word & 0x00E0) >> 5) as u8;
-        let rs = ((word & 0x0700) >> 8) as u8;
-        let func = (word & 0xF800) >> 11;
-        let imm_i5 = (word & 0xF800) >> 11;
-        let imm_i5_sext = ((word & 0xF800) as i16) >> 11;
-        let imm_i8 = (word & 0xFF00) >> 8;
-        let imm_i8_sext = ((word & 0xFF00) as i16) >> 8;
-
-        match opcode {
-            0b00000 => match func {
-                0b0001 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "mov".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.mov(rd, rs);
-                }
-                0b0010 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "add".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.add(rd, rs);
-                }
-                0b0011 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "sub".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.sub(rd, rs);
-                }
-                0b0100 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "and".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.and(rd, rs);
-                }
-                0b0101 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "or".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.or(rd, rs);
-                }
-                0b0110 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "xor".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.xor(rd, rs);
-                }
-                0b0111 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "sll".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.sll(rd, rs);
-                }
-                0b1000 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "srl".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.srl(rd, rs);
-                }
-                0b1001 => {
-                    let inst_info = InstInfo::R {
-                        mnemonic: "sra".to_string(),
-                        rd,
-                        rs,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    self.cpu.sra(rd, rs);
-                }
-                _ => Err(Error::UnknownInstruction(word))?,
-            },
-            0b00001 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "addi".to_string(),
-                    rd,
-                    rs,
-                    imm: Some(imm_i5),
-                    imm_sext: None,
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.addi(rd, rs, imm_i5);
-            }
-            0b00010 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "subi".to_string(),
-                    rd,
-                    rs,
-                    imm: Some(imm_i5),
-                    imm_sext: None,
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.subi(rd, rs, imm_i5);
-            }
-            0b00011 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "beq".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.beq(rd, rs, imm_i5_sext);
-            }
-            0b00100 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "bnq".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.bnq(rd, rs, imm_i5_sext);
+    // Executes at most max_steps instructions. Returns None if none of them stopped execution.
+    pub fn run_steps(&mut self, max_steps: u64) -> Option<StopReason> {
+        for _ in 0..max_steps {
+            if let Err(e) = self.step() {
+                return Some(StopReason::Error(e));
             }
-            0b00101 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "blt".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.blt(rd, rs, imm_i5_sext);
+            if let Some(divergence) = self.divergence.take() {
+                return Some(StopReason::Divergence(divergence));
             }
-            0b00110 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "bge".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.bge(rd, rs, imm_i5_sext);
+            if let Some(hit) = self.watch_hit.take() {
+                return Some(StopReason::Watchpoint(hit));
             }
-            0b00111 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "bltu".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.bltu(rd, rs, imm_i5_sext);
+            if let Some(id) = self.breakpoints.hit(&self.cpu, &self.memory) {
+                return Some(StopReason::Breakpoint(id));
             }
-            0b01000 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "bgeu".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.bgeu(rd, rs, imm_i5_sext);
-            }
-            0b01001 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "jalr".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                self.cpu.jalr(rd, rs, imm_i5_sext);
-            }
-            0b01010 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "lh".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                let address = self.cpu.get_gr(rs).wrapping_add(imm_i5_sext as u16);
-                let data = self.memory.read_from_memory(&address, true)?;
-                self.cpu.set_gr(rd, data);
-            }
-            0b01011 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "lhu".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                let address = self.cpu.get_gr(rs).wrapping_add(imm_i5_sext as u16);
-                let data = self.memory.read_from_memory(&address, false)?;
-                let data = data & 0x00ff;
-                self.cpu.set_gr(rd, data);
-            }
-            0b01100 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "lw".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                let address = self.cpu.get_gr(rs).wrapping_add(imm_i5_sext as u16);
-                let data = self.memory.read_from_memory(&address, false)?;
-                self.cpu.set_gr(rd, data);
-            }
-            0b01101 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "sh".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                let address = self.cpu.get_gr(rs).wrapping_add(imm_i5_sext as u16);
-                let data = self.cpu.get_gr(rd);
-                self.memory.write_to_memory(&address, data, true)?;
-            }
-            0b01110 => {
-                let inst_info = InstInfo::I5 {
-                    mnemonic: "sw".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i5_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
-
-                let address = self.cpu.get_gr(rs).wrapping_add(imm_i5_sext as u16);
-                let data = self.cpu.get_gr(rd);
-                self.memory.write_to_memory(&address, data, false)?;
-            }
-            0b10000 => {
-                let inst_info = InstInfo::I8 {
-                    mnemonic: "jal".to_string(),
-                    rd,
-                    rs,
-                    imm: None,
-                    imm_sext: Some(imm_i8_sext),
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
+        }
+        None
+    }
 
-                self.cpu.jal(rd, imm_i8_sext);
-            }
-            0b10001 => {
-                let inst_info = InstInfo::I8 {
-                    mnemonic: "lil".to_string(),
-                    rd,
-                    rs,
-                    imm: Some(imm_i8),
-                    imm_sext: None,
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
+    // Undoes the last step. The undo log does not see changes made by the debugger.
+    pub fn step_back(&mut self) -> Result<(), Error> {
+        self.undo()?;
+        Ok(())
+    }
 
-                self.cpu.lil(rd, imm_i8);
+    pub fn run_back(&mut self) -> StopReason {
+        loop {
+            if let Some(reason) = self.run_back_steps(u64::MAX) {
+                return reason;
             }
-            0b10010 => {
-                let inst_info = InstInfo::I8 {
-                    mnemonic: "lih".to_string(),
-                    rd,
-                    rs,
-                    imm: Some(imm_i8),
-                    imm_sext: None,
-                };
-                Self::print_inst_info(current_pc, word, inst_info);
+        }
+    }
 
-                self.cpu.lih(rd, imm_i8);
-            }
-            0b11110 => match func {
-                0b00001 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "push".to_string(),
-                        rd,
+    // Goes back until pc reaches a breakpoint or an undone instruction hits a watchpoint,
+    // which leaves pc at that instruction. Hit counts and ignore counts are left as they are.
+    pub fn run_back_steps(&mut self, max_steps: u64) -> Option<StopReason> {
+        for _ in 0..max_steps {
+            let (access, written) = match self.undo() {
+                Ok(undone) => undone,
+                Err(e) => return Some(StopReason::Error(e)),
+            };
+            if let Some(access) = access {
+                let id =
+                    self.breakpoints
+                        .peek_watch_hit(access.address, access.size(), access.write);
+                if let Some(id) = id {
+                    let old = match self.peek(&access) {
+                        Ok(value) => value,
+                        Err(e) => return Some(StopReason::Error(e)),
                     };
-                    Self::print_inst_info(current_pc, word, inst_info);
-
-                    let data = self.cpu.get_gr(rd);
-                    self.cpu.sp -= 2;
-                    self.memory.write_to_memory(&self.cpu.sp, data, false)?;
+                    return Some(StopReason::Watchpoint(WatchHit {
+                        id,
+                        pc: self.cpu.pc,
+                        address: access.address,
+                        half: access.half,
+                        write: access.write,
+                        old,
+                        new: if access.write { written } else { old },
+                    }));
                 }
-                0b00010 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "pop".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+            }
+            if let Some(id) = self.breakpoints.peek_hit(&self.cpu, &self.memory) {
+                return Some(StopReason::Breakpoint(id));
+            }
+        }
+        None
+    }
 
-                    let data = self.memory.read_from_memory(&self.cpu.sp, false)?;
-                    self.cpu.set_gr(rd, data);
-                    self.cpu.sp += 2;
-                }
-                0b00011 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rpc".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // Moves backwards through the undo log or forwards by executing until steps() is step.
+    pub fn goto(&mut self, step: u64) -> Result<(), Error> {
+        while self.steps > step {
+            self.undo()?;
+        }
+        while self.steps < step {
+            self.step()?;
+        }
+        Ok(())
+    }
 
-                    self.cpu.rpc(rd);
-                }
-                0b00100 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rsp".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // Restores the state before the last step. Returns the memory access of the step and the
+    // value it had written.
+    fn undo(&mut self) -> Result<(Option<MemoryAccess>, u16), Error> {
+        let entry = self.history.pop().ok_or(Error::NoHistory())?;
+        let mut written = 0;
+        if let Some(access) = entry.access.filter(|a| a.write) {
+            written = self.peek(&access)?;
+            self.memory.poke(access.address, entry.old, access.half)?;
+        }
+        self.memory.load_devices(&entry.devices);
+        self.cpu = entry.cpu;
+        self.timer.state = entry.timer;
+        self.steps -= 1;
+        self.watch_hit = None;
+        self.divergence = None;
+        if let Some(comparator) = self.compare.as_mut().filter(|_| entry.retired) {
+            comparator.rewind();
+        }
+        Ok((entry.access, written))
+    }
 
-                    self.cpu.rsp(rd);
+    pub fn step(&mut self) -> Result<(), Error> {
+        let current_pc = self.cpu.pc;
+        let undo = self.history.is_enabled().then(|| self.cpu.clone());
+        self.memory.record_devices(undo.is_some());
+
+        let (word, inst) = match self.fetch(current_pc) {
+            Ok(fetched) => fetched,
+            Err(e) => match (self.fault_policy, Self::exception_for(&e)) {
+                (FaultPolicy::Exception, Some(exception)) => {
+                    self.fetch_fault(exception, undo);
+                    return Ok(());
                 }
-                0b00101 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rpsr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+                _ => return Err(e),
+            },
+        };
+        self.cpu.pc = self.cpu.pc.wrapping_add(2);
+        // a faulting instruction enters the trap handler instead of executing
+        let fault = self.fault(&inst);
+        let access = match fault {
+            Some(_) => None,
+            None => self.memory_access(&inst),
+        };
 
-                    self.cpu.rpsr(rd);
-                }
-                0b00110 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rtlr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+        let watched = if self.breakpoints.has_watchpoints() {
+            access.and_then(|access| {
+                self.breakpoints
+                    .watch_hit(access.address, access.size(), access.write)
+                    .map(|id| (id, access))
+            })
+        } else {
+            None
+        };
+        let old = match watched {
+            Some((_, access)) => self.peek(&access)?,
+            None => 0,
+        };
 
-                    self.cpu.rtlr(rd);
-                }
-                0b00111 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rthr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+        // the state before execution is only needed by the trace and the comparison
+        let before = match self.trace.is_some() || self.compare.is_some() {
+            true => {
+                let old = match &access {
+                    Some(access) => self.peek(access)?,
+                    None => 0,
+                };
+                Some((self.cpu.clone(), access, old))
+            }
+            false => None,
+        };
 
-                    self.cpu.rthr(rd);
-                }
-                0b01000 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rppc".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+        let undo = match undo {
+            Some(cpu) => {
+                let old = match access {
+                    Some(access) if access.write => self.peek(&access)?,
+                    _ => 0,
+                };
+                Some(UndoEntry {
+                    cpu,
+                    timer: self.timer.state,
+                    access,
+                    old,
+                    devices: vec![],
+                    retired: true,
+                })
+            }
+            None => None,
+        };
 
-                    self.cpu.rppc(rd);
-                }
-                0b01001 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "rppsr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+        if self.write_policy == WritePolicy::Warn {
+            if let Some(Err(e @ MemoryError::NotWritable(_))) =
+                access.map(|access| self.memory.check(&access))
+            {
+                eprintln!("warning : ignored a store at 0x{:04x}, {}", current_pc, e);
+            }
+        }
+        match fault {
+            Some(exception) => self.cpu.exception(exception, current_pc),
+            None => self.cpu.execute(&inst, &mut self.memory)?,
+        }
+        self.memory.tick();
+        let taken = self.cpu.pc != current_pc.wrapping_add(2);
+        if self
+            .timer
+            .tick(&mut self.cpu.tr, timer::cycles(&inst, taken))
+        {
+            self.memory.raise_irq(1 << intc::TIMER_LINE);
+        }
+        if let Some(mut entry) = undo {
+            entry.devices = self.memory.take_devices();
+            self.history.push(entry);
+        }
+        if let Some((cpu, access, old)) = before {
+            let written = match fault {
+                Some(_) => trace::written_registers(&Instruction::Trap),
+                None => trace::written_registers(&inst),
+            };
+            let regs: Vec<RegWrite> = written
+                .into_iter()
+                .map(|reg| RegWrite {
+                    reg,
+                    old: reg.read(&cpu),
+                    new: reg.read(&self.cpu),
+                })
+                .collect();
+            let memory = match access {
+                Some(access) => Some(MemoryRecord {
+                    access,
+                    old,
+                    new: self.peek(&access)?,
+                }),
+                None => None,
+            };
+            let event = TraceEvent {
+                step: self.steps,
+                pc: current_pc,
+                word,
+                inst: &inst,
+                regs: &regs,
+                memory,
+                symbol: self.symbols.describe(current_pc),
+            };
+            if let Some(sink) = &mut self.trace {
+                sink.trace(&event);
+            }
+            if let Some(comparator) = &mut self.compare {
+                self.divergence = comparator.check(&event);
+            }
+        }
+        // taken between instructions, so ppc is the next instruction
+        if self.cpu.interrupts_enabled() && self.memory.irq() {
+            self.cpu.interrupt();
+        }
+        self.steps += 1;
+
+        if let Some((id, access)) = watched {
+            self.watch_hit = Some(WatchHit {
+                id,
+                pc: current_pc,
+                address: access.address,
+                half: access.half,
+                write: access.write,
+                old,
+                new: self.peek(&access)?,
+            });
+        }
 
-                    self.cpu.rppsr(rd);
-                }
-                0b01010 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "wsp".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+        Ok(())
+    }
 
-                    self.cpu.wsp(rd);
-                }
-                0b01011 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "wpsr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    fn fetch(&self, pc: u16) -> Result<(u16, Instruction), Error> {
+        if pc & 1 != 0 {
+            Err(MemoryError::Misaligned(pc))?
+        }
+        let word = self.memory.fetch(pc)?;
+        if word == 0x0 {
+            return Err(Error::DebugInterrupt());
+        }
+        Ok((word, decode(word)?))
+    }
 
-                    self.cpu.wpsr(rd);
-                }
-                0b01100 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "wtlr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // The exception an error becomes under FaultPolicy::Exception.
+    fn exception_for(e: &Error) -> Option<Exception> {
+        match e {
+            Error::UnknownInstruction(_) => Some(Exception::IllegalInstruction),
+            Error::MemoryError(MemoryError::Misaligned(_)) => Some(Exception::Misaligned),
+            Error::MemoryError(_) => Some(Exception::BusError),
+            _ => None,
+        }
+    }
 
-                    self.cpu.wtlr(rd);
-                }
-                0b01101 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "wthr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // Enters the trap handler for an instruction that could not be fetched or decoded. Nothing
+    // was executed, so the step is neither traced nor compared.
+    fn fetch_fault(&mut self, exception: Exception, undo: Option<Cpu>) {
+        if let Some(cpu) = undo {
+            self.history.push(UndoEntry {
+                cpu,
+                timer: self.timer.state,
+                access: None,
+                old: 0,
+                devices: self.memory.take_devices(),
+                retired: false,
+            });
+        }
+        let pc = self.cpu.pc;
+        self.cpu.exception(exception, pc);
+        self.steps += 1;
+    }
 
-                    self.cpu.wthr(rd);
+    // The exception entered instead of executing inst, if any.
+    fn fault(&self, inst: &Instruction) -> Option<Exception> {
+        if let Some(protection) = self.protection.as_ref().filter(|_| self.cpu.is_user_mode()) {
+            if privilege::is_privileged(inst) {
+                return Some(Exception::PrivilegedInstruction);
+            }
+            if let Some(access) = self.memory_access(inst) {
+                if protection.is_protected(&access) {
+                    return Some(Exception::ProtectionFault);
                 }
-                0b01110 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "wppc".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+            }
+        }
+        let access = self.memory_access(inst)?;
+        if let Err(MemoryError::NotWritable(_)) = self.memory.check(&access) {
+            return (self.write_policy == WritePolicy::Exception).then_some(Exception::BusError);
+        }
+        if self.fault_policy == FaultPolicy::Exception {
+            let checked = self.memory.check(&access).map_err(Error::from);
+            if let Err(e) = checked.and_then(|_| self.peek(&access)) {
+                return Self::exception_for(&e);
+            }
+        }
+        None
+    }
 
-                    self.cpu.wppc(rd);
-                }
-                0b01111 => {
-                    let inst_info = InstInfo::C1 {
-                        mnemonic: "wppsr".to_string(),
-                        rd,
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // The memory access inst will make, after the alignment rule of the memory.
+    fn memory_access(&self, inst: &Instruction) -> Option<MemoryAccess> {
+        self.cpu
+            .memory_access(inst)
+            .map(|access| self.memory.align(access))
+    }
 
-                    self.cpu.wppsr(rd);
-                }
-                _ => Err(Error::UnknownInstruction(word))?,
-            },
-            0b11111 => match func {
-                0b00001 => {
-                    let inst_info = InstInfo::C2 {
-                        mnemonic: "rfi".to_string(),
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    fn peek(&self, access: &MemoryAccess) -> Result<u16, Error> {
+        if access.half {
+            Ok(self.memory.read_byte(access.address)? as u16)
+        } else {
+            Ok(self.memory.read_from_memory(&access.address, false)?)
+        }
+    }
 
-                    self.cpu.rfi();
-                }
-                0b00010 => {
-                    let inst_info = InstInfo::C2 {
-                        mnemonic: "rtr".to_string(),
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // A hexadecimal address, or a label of the symbol file with an optional offset (fail+0x4).
+    fn parse_address(&self, addr: &str, example: &str) -> Option<u16> {
+        if !addr.starts_with("0x") {
+            if let Some(addr) = self.symbols.resolve(addr) {
+                return Some(addr);
+            }
+            match self.symbols.is_empty() {
+                true => eprintln!("address is only hexadecimal\ne.g. : {}", example),
+                false => eprintln!("unknown label : {}\ne.g. : {}", addr, example),
+            }
+            return None;
+        }
+        match u16::from_str_radix(addr.trim_start_matches("0x"), 16) {
+            Ok(addr) => Some(addr),
+            Err(_) => {
+                eprintln!("invalid address\ne.g. : {}", example);
+                None
+            }
+        }
+    }
 
-                    self.cpu.rtr();
-                }
-                0b00011 => {
-                    let inst_info = InstInfo::C2 {
-                        mnemonic: "wtr".to_string(),
-                    };
-                    Self::print_inst_info(current_pc, word, inst_info);
+    // "0xb02a <fail+0x4>"
+    fn describe(&self, address: u16) -> String {
+        match self.symbols.describe(address) {
+            Some(label) => format!("0x{:04x} <{}>", address, label),
+            None => format!("0x{:04x}", address),
+        }
+    }
 
-                    self.cpu.wtr();
-                }
-                _ => {
-                    if word == 0xFFFF {
-                        let inst_info = InstInfo::Trap {
-                            mnemonic: "trap".to_string(),
-                        };
-                        Self::print_inst_info(current_pc, word, inst_info);
+    fn load_state_file(&mut self, path: &str) -> Result<(), String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        let snapshot = Snapshot::decode(&bytes).map_err(|e| e.to_string())?;
+        self.restore(snapshot).map_err(|e| e.to_string())
+    }
 
-                        self.cpu.trap();
-                    } else {
-                        Err(Error::UnknownInstruction(word))?
-                    }
-                }
-            },
-            _ => Err(Error::UnknownInstruction(word))?,
+    fn print_inst_at(&self, step: u64, pc: u16) {
+        let Ok(word) = self.memory.read_from_memory(&pc, false) else {
+            return;
         };
-
-        Ok(())
+        let Ok(inst) = decode(word) else {
+            return;
+        };
+        let event = TraceEvent {
+            step,
+            pc,
+            word,
+            inst: &inst,
+            regs: &[],
+            memory: None,
+            symbol: self.symbols.describe(pc),
+        };
+        println!("{}", trace::format_text(&event));
     }
 
-    fn print_regs(&self) {
+    pub fn print_regs(&self) {
         println!(
             " x0 : 0x{:04x} x1 : 0x{:04x} x2 : 0x{:04x} x3 : 0x{:04x}",
             self.cpu.get_gr(0),
@@ -719,286 +1249,1025 @@ impl Zktc {
         );
     }
 
-    fn set_break(&mut self, address: u16) {
-        self.break_point = Some(address);
+    pub fn set_break(&mut self, address: u16) -> usize {
+        self.breakpoints.add(address)
     }
 
-    fn print_inst_info(current_pc: u16, word: u16, inst_info: InstInfo) {
-        match inst_info {
-            InstInfo::R { mnemonic, rd, rs } => {
-                println!(
-                    "pc : 0x{:04x} {:016b} {} x{} x{}",
-                    current_pc, word, mnemonic, rd, rs
-                )
-            }
-            InstInfo::I5 {
-                mnemonic,
-                rd,
-                rs,
-                imm,
-                imm_sext,
-            } => {
-                if let Some(imm) = imm {
-                    println!(
-                        "pc : 0x{:04x} {:016b} {} x{} x{} {}",
-                        current_pc, word, mnemonic, rd, rs, imm
-                    )
-                } else {
-                    println!(
-                        "pc : 0x{:04x} {:016b} {} x{} x{} {}",
-                        current_pc,
-                        word,
-                        mnemonic,
-                        rd,
-                        rs,
-                        imm_sext.unwrap()
-                    )
-                }
-            }
-            InstInfo::I8 {
-                mnemonic,
-                rd,
-                imm,
-                imm_sext,
-                ..
-            } => {
-                if let Some(imm) = imm {
-                    println!(
-                        "pc : 0x{:04x} {:016b} {} x{} {}",
-                        current_pc, word, mnemonic, rd, imm
-                    )
-                } else {
-                    println!(
-                        "pc : 0x{:04x} {:016b} {} x{} {}",
-                        current_pc,
-                        word,
-                        mnemonic,
-                        rd,
-                        imm_sext.unwrap()
-                    )
-                }
-            }
-            InstInfo::C1 { mnemonic, rd } => {
-                println!(
-                    "pc : 0x{:04x} {:016b} {} x{} ",
-                    current_pc, word, mnemonic, rd
-                )
-            }
-            InstInfo::C2 { mnemonic } => {
-                println!("pc : 0x{:04x} {:016b} {} ", current_pc, word, mnemonic)
-            }
-            InstInfo::Trap { mnemonic } => {
-                println!("pc : 0x{:04x} {:016b} {} ", current_pc, word, mnemonic)
+    fn print_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("no breakpoints");
+            return;
+        }
+        println!("num  type        enb  address         hits  ignore");
+        for b in self.breakpoints.iter() {
+            let (kind, address) = match b.kind {
+                BreakpointKind::Code => ("breakpoint", format!("0x{:04x}", b.address)),
+                BreakpointKind::Watch(kind) => (
+                    match kind {
+                        WatchKind::Write => "watch",
+                        WatchKind::Read => "rwatch",
+                        WatchKind::Access => "awatch",
+                    },
+                    format!(
+                        "0x{:04x}-0x{:04x}",
+                        b.address,
+                        b.address as u32 + b.len as u32 - 1
+                    ),
+                ),
+            };
+            println!(
+                "{:<4} {:<11} {:<4} {:<15} {:<5} {}",
+                b.id,
+                kind,
+                if b.enabled { "y" } else { "n" },
+                address,
+                b.hit_count,
+                b.ignore_count
+            );
+            if let Some(condition) = &b.condition {
+                println!("     stop only if {}", condition.text);
             }
         }
     }
+
+    pub fn eval(&self, src: &str) -> Result<u32, expr::ExprError> {
+        expr::parse(src)?.eval(&self.cpu, &self.memory)
+    }
+
+    fn print_watch_hit(hit: &WatchHit) {
+        let width = if hit.half { 2 } else { 4 };
+        if hit.write {
+            println!(
+                "watchpoint {} : write 0x{:04x} old 0x{:0w$x} new 0x{:0w$x} (pc 0x{:04x})",
+                hit.id,
+                hit.address,
+                hit.old,
+                hit.new,
+                hit.pc,
+                w = width
+            );
+        } else {
+            println!(
+                "watchpoint {} : read 0x{:04x} value 0x{:0w$x} (pc 0x{:04x})",
+                hit.id,
+                hit.address,
+                hit.new,
+                hit.pc,
+                w = width
+            );
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
 
     use super::*;
+    use loader::Image;
+    use machine::RegionKind;
+    use memory::MemoryError;
 
     #[test]
     fn mov_test() {
-        run_test("test/mem/mov_test.mem");
+        run_test("test/asm/mov_test.asm");
     }
 
     #[test]
     fn add_test() {
-        run_test("test/mem/add_test.mem");
+        run_test("test/asm/add_test.asm");
     }
 
     #[test]
     fn sub_test() {
-        run_test("test/mem/sub_test.mem");
+        run_test("test/asm/sub_test.asm");
     }
 
     #[test]
     fn and_test() {
-        run_test("test/mem/sub_test.mem");
+        run_test("test/asm/and_test.asm");
     }
 
     #[test]
     fn or_test() {
-        run_test("test/mem/or_test.mem");
+        run_test("test/asm/or_test.asm");
     }
 
     #[test]
     fn xor_test() {
-        run_test("test/mem/xor_test.mem");
+        run_test("test/asm/xor_test.asm");
     }
 
     #[test]
     fn sll_test() {
-        run_test("test/mem/sll_test.mem");
+        run_test("test/asm/sll_test.asm");
     }
 
     #[test]
     fn srl_test() {
-        run_test("test/mem/srl_test.mem");
+        run_test("test/asm/srl_test.asm");
     }
 
     #[test]
     fn sra_test() {
-        run_test("test/mem/sra_test.mem");
+        run_test("test/asm/sra_test.asm");
     }
 
     #[test]
     fn addi_test() {
-        run_test("test/mem/addi_test.mem");
+        run_test("test/asm/addi_test.asm");
     }
 
     #[test]
     fn subi_test() {
-        run_test("test/mem/subi_test.mem");
+        run_test("test/asm/subi_test.asm");
     }
 
     #[test]
     fn beq_test() {
-        run_test("test/mem/beq_test.mem");
+        run_test("test/asm/beq_test.asm");
     }
 
     #[test]
     fn bnq_test() {
-        run_test("test/mem/bnq_test.mem");
+        run_test("test/asm/bnq_test.asm");
     }
 
     #[test]
     fn blt_test() {
-        run_test("test/mem/blt_test.mem");
+        run_test("test/asm/blt_test.asm");
     }
 
     #[test]
     fn bge_test() {
-        run_test("test/mem/bge_test.mem");
+        run_test("test/asm/bge_test.asm");
     }
 
     #[test]
     fn bltu_test() {
-        run_test("test/mem/bltu_test.mem");
+        run_test("test/asm/bltu_test.asm");
     }
 
     #[test]
     fn bgeu_test() {
-        run_test("test/mem/bgeu_test.mem");
+        run_test("test/asm/bgeu_test.asm");
     }
 
     #[test]
     fn jalr_test() {
-        run_test("test/mem/jalr_test.mem");
+        run_test("test/asm/jalr_test.asm");
     }
 
     #[test]
     fn lh_test() {
-        run_test("test/mem/lh_test.mem");
+        run_test("test/asm/lh_test.asm");
     }
 
     #[test]
     fn lhu_test() {
-        run_test("test/mem/lhu_test.mem");
+        run_test("test/asm/lhu_test.asm");
     }
 
     #[test]
     fn lw_test() {
-        run_test("test/mem/lw_test.mem");
+        run_test("test/asm/lw_test.asm");
     }
 
     #[test]
     fn sh_test() {
-        run_test("test/mem/sh_test.mem");
+        run_test("test/asm/sh_test.asm");
     }
 
     #[test]
     fn sw_test() {
-        run_test("test/mem/sw_test.mem");
+        run_test("test/asm/sw_test.asm");
     }
 
     #[test]
     fn jal_test() {
-        run_test("test/mem/jal_test.mem");
+        run_test("test/asm/jal_test.asm");
     }
 
     #[test]
     fn lil_test() {
-        run_test("test/mem/lil_test.mem");
+        run_test("test/asm/lil_test.asm");
     }
 
     #[test]
     fn lih_test() {
-        run_test("test/mem/lih_test.mem");
+        run_test("test/asm/lih_test.asm");
     }
 
     #[test]
     fn push_test() {
-        run_test("test/mem/push_test.mem");
+        run_test("test/asm/push_test.asm");
     }
 
     #[test]
     fn pop_test() {
-        run_test("test/mem/pop_test.mem");
+        run_test("test/asm/pop_test.asm");
     }
 
     #[test]
     fn rpc_test() {
-        run_test("test/mem/rpc_test.mem");
+        run_test("test/asm/rpc_test.asm");
     }
 
     #[test]
     fn rsp_test() {
-        run_test("test/mem/rsp_test.mem");
+        run_test("test/asm/rsp_test.asm");
     }
 
     #[test]
     fn rpsr_test() {
-        run_test("test/mem/rpsr_test.mem");
+        run_test("test/asm/rpsr_test.asm");
     }
     #[test]
     fn rtlr_test() {
-        run_test("test/mem/rtlr_test.mem");
+        run_test("test/asm/rtlr_test.asm");
     }
 
     #[test]
     fn rppc_test() {
-        run_test("test/mem/rppc_test.mem");
+        run_test("test/asm/rppc_test.asm");
     }
 
     #[test]
     fn rppsr_test() {
-        run_test("test/mem/rppsr_test.mem");
+        run_test("test/asm/rppsr_test.asm");
     }
 
     #[test]
     fn wsp_test() {
-        run_test("test/mem/wsp_test.mem");
+        run_test("test/asm/wsp_test.asm");
     }
 
     #[test]
     fn wpsr_test() {
-        run_test("test/mem/wpsr_test.mem");
+        run_test("test/asm/wpsr_test.asm");
     }
 
     #[test]
     fn wtlr_test() {
-        run_test("test/mem/wtlr_test.mem");
+        run_test("test/asm/wtlr_test.asm");
     }
 
     #[test]
     fn wthr_test() {
-        run_test("test/mem/wthr_test.mem");
+        run_test("test/asm/wthr_test.asm");
     }
 
     #[test]
     fn wppc_test() {
-        run_test("test/mem/wppc_test.mem");
+        run_test("test/asm/wppc_test.asm");
     }
     #[test]
     fn wppsr_test() {
-        run_test("test/mem/wppsr_test.mem");
+        run_test("test/asm/wppsr_test.asm");
     }
 
     // cannot test for C2 instructions
 
+    #[test]
+    fn run_stops_at_breakpoint() {
+        let mut zktc = test_setup("test/asm/add_test.asm");
+        let first = zktc.set_break(0xb004);
+        let second = zktc.set_break(0xb008);
+        zktc.breakpoints_mut().set_ignore_count(second, 1).unwrap();
+        assert_eq!(zktc.run(), StopReason::Breakpoint(first));
+        assert_eq!(zktc.cpu.pc, 0xb004);
+        assert_eq!(zktc.run(), StopReason::Error(Error::DebugInterrupt()));
+        assert_eq!(zktc.breakpoints().get(second).unwrap().hit_count, 1);
+    }
+
+    #[test]
+    fn run_stops_at_watchpoint() {
+        let src = "
+            lil x1, 0x1234@l
+            lih x2, 0x1234@h
+            or x1, x2
+            addi x2, x0, 16
+            sw x1, x2, 0
+            sh x0, x2, 1
+            lhu x3, x2, 1
+        ";
+        let mut zktc =
+            Zktc::new(asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(), vec![]).unwrap();
+        let write = zktc
+            .breakpoints_mut()
+            .add_watch(WatchKind::Write, 0x0011, 1);
+        let access = zktc
+            .breakpoints_mut()
+            .add_watch(WatchKind::Access, 0x0011, 1);
+
+        let StopReason::Watchpoint(hit) = zktc.run() else {
+            panic!("watchpoint was not hit");
+        };
+        assert_eq!((hit.id, hit.pc), (write, 0xb008));
+        assert_eq!((hit.address, hit.old, hit.new), (0x0010, 0x0000, 0x1234));
+
+        let StopReason::Watchpoint(hit) = zktc.run() else {
+            panic!("watchpoint was not hit");
+        };
+        assert!(hit.half && hit.write);
+        assert_eq!((hit.address, hit.old, hit.new), (0x0011, 0x12, 0x00));
+
+        let StopReason::Watchpoint(hit) = zktc.run() else {
+            panic!("watchpoint was not hit");
+        };
+        assert_eq!(hit.id, access);
+        assert!(hit.half && !hit.write);
+        assert_eq!(zktc.run(), StopReason::Error(Error::DebugInterrupt()));
+    }
+
+    #[test]
+    fn run_stops_at_conditional_breakpoint() {
+        let mut zktc = test_setup("test/asm/add_test.asm");
+        zktc.set_break(0xb022);
+        let id = zktc.set_break(0xb026);
+        let condition = Condition::parse("x2 == 2 && [0xfffe] == 0").unwrap();
+        zktc.breakpoints_mut()
+            .set_condition(1, Some(Condition::parse("x2 == 2").unwrap()))
+            .unwrap();
+        zktc.breakpoints_mut()
+            .set_condition(id, Some(condition))
+            .unwrap();
+        assert_eq!(zktc.run(), StopReason::Breakpoint(id));
+        assert_eq!(zktc.eval("pc"), Ok(0xb026));
+    }
+
+    #[test]
+    fn run_steps_stops_at_limit() {
+        let mut zktc = test_setup("test/asm/add_test.asm");
+        assert_eq!(zktc.run_steps(3), None);
+        assert_eq!((zktc.steps(), zktc.cpu.pc), (3, 0xb006));
+        assert_eq!(
+            zktc.run_steps(1000),
+            Some(StopReason::Error(Error::DebugInterrupt()))
+        );
+    }
+
+    struct PcSink(std::sync::Arc<std::sync::Mutex<Vec<(u64, u16)>>>);
+
+    impl TraceSink for PcSink {
+        fn trace(&mut self, event: &TraceEvent) {
+            self.0.lock().unwrap().push((event.step, event.pc));
+        }
+    }
+
+    #[test]
+    fn trace_sink_sees_each_step() {
+        let mut zktc = test_setup("test/asm/add_test.asm");
+        assert!(!zktc.is_tracing());
+        zktc.run_steps(1);
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        zktc.set_trace(Some(Box::new(PcSink(seen.clone()))));
+        zktc.run_steps(2);
+        zktc.set_trace(None);
+        zktc.run_steps(1);
+        assert_eq!(*seen.lock().unwrap(), vec![(1, 0xb002), (2, 0xb004)]);
+    }
+
+    struct LineSink(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl TraceSink for LineSink {
+        fn trace(&mut self, event: &TraceEvent) {
+            self.0.lock().unwrap().push(trace::format_jsonl(event));
+        }
+    }
+
+    #[test]
+    fn trace_records_register_and_memory_writes() {
+        let src = "
+            addi x2, x0, 16
+            lil x1, 0xff
+            sh x1, x2, 1
+            lhu x1, x2, 0
+        ";
+        let mut zktc =
+            Zktc::new(asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(), vec![]).unwrap();
+        let lines = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        zktc.set_trace(Some(Box::new(LineSink(lines.clone()))));
+        zktc.run_steps(4);
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].contains(r#""regs":[{"reg":"x2","old":0,"new":16}],"mem":[]"#));
+        assert!(lines[2]
+            .contains(r#""regs":[],"mem":[{"addr":17,"size":1,"write":true,"old":0,"new":255}]"#));
+        assert!(lines[3].contains(
+            r#""regs":[{"reg":"x1","old":255,"new":0}],"mem":[{"addr":16,"size":1,"write":false,"old":0,"new":0}]"#
+        ));
+    }
+
+    #[test]
+    fn commands_and_traces_use_symbols() {
+        let src = std::fs::read_to_string("test/asm/add_test.asm").unwrap();
+        let (rom, symbols) = asm::assemble_with_symbols(&src, memory::ROM_LOW_ADDRESS).unwrap();
+        let pass = symbols.resolve("pass").unwrap();
+        let mut machine = Machine::load("test/machine.toml").unwrap();
+        machine
+            .set_image(RegionKind::Rom, &Image::relative(rom))
+            .unwrap();
+        let mut zktc = Zktc::from_machine(&machine).unwrap();
+        zktc.set_symbols(symbols);
+        assert_eq!(
+            zktc.parse_address("expect", "m expect 1"),
+            zktc.symbols.resolve("expect")
+        );
+        assert_eq!(
+            zktc.parse_address("fail+0x4", "d fail+0x4 1"),
+            zktc.symbols.resolve("fail").map(|a| a + 4)
+        );
+        assert_eq!(zktc.parse_address("nowhere", "b pass"), None);
+        // a dump past 0xffff wraps
+        zktc.do_cmd(vec!["m", "0xfffe", "2"]).unwrap();
+
+        zktc.do_cmd(vec!["b", "pass"]).unwrap();
+        zktc.do_cmd(vec!["b", "nowhere"]).unwrap();
+        assert_eq!(zktc.breakpoints.iter().count(), 1);
+        assert_eq!(zktc.run(), StopReason::Breakpoint(1));
+        assert_eq!(zktc.cpu.pc, pass);
+
+        let lines = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        zktc.set_trace(Some(Box::new(LineSink(lines.clone()))));
+        zktc.run_steps(2);
+        let lines = lines.lock().unwrap();
+        assert!(lines[0].ends_with(r#","symbol":"pass"}"#));
+        assert!(lines[1].ends_with(r#","symbol":"pass+0x2"}"#));
+    }
+
+    #[test]
+    fn run_stops_at_divergence() {
+        let src = "
+            addi x1, x0, 1
+            addi x1, x1, 1
+            sw x1, x0, 8
+        ";
+        let mut zktc =
+            Zktc::new(asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(), vec![]).unwrap();
+        let reference = "b000 x1 1 - -\nb002 x1 2 - -\nb004 - - 8 3\n";
+        let columns = compare::Columns::default();
+        let records = compare::parse_reference(reference, &columns).unwrap();
+        zktc.set_compare(Some(Comparator::new(records, columns)));
+
+        let StopReason::Divergence(divergence) = zktc.run() else {
+            panic!("trace did not diverge");
+        };
+        assert_eq!(divergence.step, 2);
+        assert_eq!(divergence.actual.mem, Some((0x8, 2)));
+        assert_eq!(divergence.expected.unwrap().commit.mem, Some((0x8, 3)));
+    }
+
+    #[test]
+    fn step_back_restores_registers_and_memory() {
+        let mut zktc = test_setup("test/asm/push_test.asm");
+        zktc.set_history_budget(history::DEFAULT_BUDGET);
+        let start = (zktc.cpu.clone(), zktc.read_memory(0, false).unwrap());
+        zktc.run();
+        let steps = zktc.steps();
+        let end = (zktc.cpu.clone(), zktc.read_memory(0, false).unwrap());
+        assert_ne!(start, end);
+
+        zktc.goto(0).unwrap();
+        assert_eq!(
+            (zktc.cpu.clone(), zktc.read_memory(0, false).unwrap()),
+            start
+        );
+        assert_eq!(zktc.step_back(), Err(Error::NoHistory()));
+        zktc.goto(steps).unwrap();
+        assert_eq!((zktc.cpu.clone(), zktc.read_memory(0, false).unwrap()), end);
+    }
+
+    #[test]
+    fn run_back_stops_at_watchpoint() {
+        let src = "
+            addi x2, x0, 16
+            addi x1, x0, 1
+            sw x1, x2, 0
+            addi x1, x0, 2
+            sw x1, x2, 0
+            lw x3, x2, 0
+        ";
+        let mut zktc =
+            Zktc::new(asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(), vec![]).unwrap();
+        zktc.set_history_budget(history::DEFAULT_BUDGET);
+        zktc.run();
+        let id = zktc
+            .breakpoints_mut()
+            .add_watch(WatchKind::Write, 0x0010, 2);
+
+        let StopReason::Watchpoint(hit) = zktc.run_back() else {
+            panic!("watchpoint was not hit");
+        };
+        assert_eq!((hit.id, hit.pc, hit.old, hit.new), (id, 0xb008, 1, 2));
+        assert_eq!((zktc.cpu.pc, zktc.steps()), (0xb008, 4));
+        let StopReason::Watchpoint(hit) = zktc.run_back() else {
+            panic!("watchpoint was not hit");
+        };
+        assert_eq!((hit.pc, hit.old, hit.new), (0xb004, 0, 1));
+        assert_eq!(zktc.run_back(), StopReason::Error(Error::NoHistory()));
+        assert_eq!(zktc.breakpoints().get(id).unwrap().hit_count, 0);
+    }
+
+    struct Sent(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Sent {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn step_back_restores_devices() {
+        let src = "
+            lih x1, 0xa0
+            addi x2, x0, 8
+            sw x2, x1, 0
+            lih x3, 0x90
+            lw x4, x3, 0
+            sw x2, x3, 0
+        ";
+        let mut zktc =
+            Zktc::new(asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(), vec![]).unwrap();
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let device = uart::Uart::new(Box::new(Sent(sent.clone())), Some(receiver));
+        zktc.memory_mut()
+            .map("uart", uart::DEFAULT_BASE, uart::SIZE, Box::new(device))
+            .unwrap();
+        sender.send(b'a').unwrap();
+        zktc.memory_mut().tick();
+        zktc.raise_irq(3);
+        zktc.set_history_budget(history::DEFAULT_BUDGET);
+
+        assert_eq!(zktc.run_steps(6), None);
+        assert_eq!(zktc.read_memory(intc::DEFAULT_BASE, false), Ok(0));
+        assert_eq!(zktc.read_gr(4), b'a' as u16);
+        assert_eq!(*sent.lock().unwrap(), vec![8]);
+
+        // the pending line is set again and the byte can be read again, nothing is sent
+        zktc.goto(0).unwrap();
+        assert_eq!(zktc.read_memory(intc::DEFAULT_BASE, false), Ok(8));
+        assert_eq!(zktc.read_memory(uart::DEFAULT_BASE, true), Ok(b'a' as u16));
+        assert_eq!(*sent.lock().unwrap(), vec![8]);
+        zktc.goto(5).unwrap();
+        assert_eq!(zktc.read_gr(4), b'a' as u16);
+    }
+
+    #[test]
+    fn restore_snapshot() {
+        let mut zktc = test_setup("test/asm/push_test.asm");
+        zktc.set_break(0xb020);
+        zktc.run();
+        let bytes = zktc.snapshot().encode();
+        let (cpu, steps) = (zktc.cpu.clone(), zktc.steps());
+        zktc.run();
+
+        let mut restored =
+            Zktc::from_machine(&Machine::load("test/machine.toml").unwrap()).unwrap();
+        restored.restore(Snapshot::decode(&bytes).unwrap()).unwrap();
+        assert_eq!((restored.cpu.clone(), restored.steps()), (cpu, steps));
+        assert_eq!(restored.breakpoints().get(1).unwrap().hit_count, 1);
+        assert_eq!(restored.set_break(0xb030), 2);
+        restored.breakpoints_mut().delete(2).unwrap();
+        restored.run();
+        assert_eq!(restored.cpu, zktc.cpu);
+        assert_eq!(restored.snapshot(), zktc.snapshot());
+
+        // nothing is restored when a device state does not load
+        let mut bad = Snapshot::decode(&bytes).unwrap();
+        bad.cpu.pc = 0;
+        bad.devices
+            .iter_mut()
+            .find(|(name, _)| name == "rom")
+            .unwrap()
+            .1 = vec![];
+        let before = restored.snapshot();
+        assert!(matches!(
+            restored.restore(bad),
+            Err(Error::SnapshotError(SnapshotError::InvalidDeviceState(..)))
+        ));
+        assert_eq!(restored.snapshot(), before);
+    }
+
+    struct Ticks(u8);
+
+    impl bus::Device for Ticks {
+        fn write8(&mut self, _offset: u16, data: u8) {
+            self.0 = data;
+        }
+
+        fn peek8(&self, _offset: u16) -> u8 {
+            self.0
+        }
+
+        fn tick(&mut self) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn devices_on_the_bus() {
+        let src = "
+            lil x2, 0x9000@l
+            lih x3, 0x9000@h
+            or x2, x3
+            addi x1, x0, 5
+            sw x1, x2, 2
+            lw x4, x2, 2
+            lhu x5, x2, 0
+        ";
+        let mut zktc =
+            Zktc::new(asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(), vec![]).unwrap();
+        let ram = bus::Ram::new(4, &[]);
+        zktc.memory_mut()
+            .map("io", 0x9000, 4, Box::new(ram))
+            .unwrap();
+        zktc.memory_mut()
+            .map("ticks", 0x9004, 1, Box::new(Ticks(0)))
+            .unwrap();
+        assert_eq!(
+            zktc.memory_mut().map("late", 0xafff, 2, Box::new(Ticks(0))),
+            Err(MemoryError::Bus(bus::BusError::Overlap(
+                "late".to_string(),
+                "rom".to_string()
+            )))
+        );
+
+        zktc.run();
+        assert_eq!((zktc.read_gr(4), zktc.read_gr(5)), (5, 0));
+        assert_eq!(zktc.read_memory(0x9004, true), Ok(7));
+        zktc.reset();
+        assert_eq!(zktc.cpu.pc, memory::ROM_LOW_ADDRESS);
+        assert_eq!(zktc.read_memory(0x9002, false), Ok(5));
+    }
+
+    #[test]
+    fn uart_rx_interrupt() {
+        let src = "
+            lil x2, 0x9000@l
+            lih x3, 0x9000@h
+            or x2, x3
+            addi x1, x0, 1
+            sw x1, x2, 4
+            addi x1, x0, 2
+            wpsr x1
+            addi x7, x0, 1
+            addi x7, x7, 1
+        ";
+        let handler = "
+            lhu x6, x2, 0
+            rfi
+        ";
+        let mut zktc = Zktc::new(
+            asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(),
+            asm::assemble(handler, 0).unwrap(),
+        )
+        .unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let uart = uart::Uart::new(Box::new(std::io::sink()), Some(receiver));
+        zktc.memory_mut()
+            .map("uart", uart::DEFAULT_BASE, uart::SIZE, Box::new(uart))
+            .unwrap();
+        sender.send(b'a').unwrap();
+
+        zktc.run();
+        assert_eq!(zktc.read_gr(6), b'a' as u16);
+        assert_eq!(zktc.read_gr(7), 2);
+        assert_eq!(zktc.cpu.ppc, memory::ROM_LOW_ADDRESS + 0xe);
+        assert_eq!(zktc.cpu.psr, cpu::PSR_INTERRUPT_ENABLE);
+    }
+
+    #[test]
+    fn timer_interrupt() {
+        let src = "
+            subi x1, x0, 3
+            subi x2, x0, 1
+            wtlr x1
+            wthr x2
+            wtr
+            addi x1, x0, 2
+            wpsr x1
+            addi x7, x0, 1
+            addi x7, x7, 1
+        ";
+        let handler = "
+            addi x6, x6, 1
+            lih x5, 0xa000@h
+            addi x4, x0, 1
+            sw x4, x5, 0
+            rfi
+        ";
+        let mut zktc = Zktc::new(
+            asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(),
+            asm::assemble(handler, 0).unwrap(),
+        )
+        .unwrap();
+        zktc.set_history_budget(history::DEFAULT_BUDGET);
+        zktc.timer_mut().rate = 1;
+
+        zktc.run();
+        assert_eq!((zktc.read_gr(6), zktc.read_gr(7)), (1, 2));
+        assert_eq!(zktc.cpu.ppc, memory::ROM_LOW_ADDRESS + 0xe);
+        assert_eq!(zktc.cpu.tr, 7);
+        assert_eq!(zktc.read_memory(intc::DEFAULT_BASE, false), Ok(0));
+
+        zktc.goto(7).unwrap();
+        assert_eq!((zktc.cpu.pc, zktc.cpu.tr), (0, 0));
+        zktc.goto(6).unwrap();
+        assert_eq!(zktc.cpu.tr, 0xffff_ffff);
+        zktc.timer_mut().rate = 0;
+        zktc.step().unwrap();
+        assert_eq!(zktc.cpu.pc, memory::ROM_LOW_ADDRESS + 0xe);
+    }
+
+    #[test]
+    fn masked_interrupt_lines() {
+        let src = "
+            lih x5, 0xa000@h
+            sw x0, x5, 2
+            addi x1, x0, 2
+            wpsr x1
+            addi x1, x0, 8
+            sw x1, x5, 2
+            addi x7, x0, 1
+        ";
+        let handler = "
+            lw x6, x5, 0
+            sw x6, x5, 0
+            rfi
+        ";
+        let mut zktc = Zktc::new(
+            asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap(),
+            asm::assemble(handler, 0).unwrap(),
+        )
+        .unwrap();
+        zktc.raise_irq(3);
+
+        zktc.run_steps(6);
+        assert_eq!(zktc.cpu.pc, 0);
+        assert_eq!(zktc.cpu.psr, cpu::PSR_TRAP | 1 << cpu::PSR_CAUSE_SHIFT);
+        assert_eq!(zktc.cpu.ppc, memory::ROM_LOW_ADDRESS + 0xc);
+        zktc.run();
+        assert_eq!((zktc.read_gr(6), zktc.read_gr(7)), (8, 1));
+        assert_eq!(zktc.cpu.psr, cpu::PSR_INTERRUPT_ENABLE);
+        assert_eq!(zktc.read_memory(intc::DEFAULT_BASE, false), Ok(0));
+    }
+
+    #[test]
+    fn strict_mode_faults() {
+        let src = "
+            lil x1, 0xb00e@l
+            lih x2, 0xb00e@h
+            or x1, x2
+            wppc x1
+            addi x3, x0, 8
+            wppsr x3
+            rfi
+            sw x0, x0, 0
+            wpsr x0
+            addi x5, x0, 1
+        ";
+        let mut zktc = with_skip_handler(src);
+        let handler_word = zktc.read_memory(0, false).unwrap();
+        zktc.set_protection(Some(Protection::new(vec![privilege::Region {
+            low: 0x0000,
+            high: 0x0fff,
+        }])));
+
+        zktc.run_steps(8);
+        assert_eq!(zktc.cpu.pc, 0);
+        assert_eq!(zktc.cpu.ppc, memory::ROM_LOW_ADDRESS + 0xe);
+        assert_eq!(zktc.cpu.ppsr, cpu::PSR_USER_MODE);
+        zktc.run();
+        assert_eq!(zktc.read_gr(5), 1);
+        assert_eq!(zktc.read_gr(7), 0x0305 + 0x0205);
+        assert_eq!(zktc.read_memory(0, false), Ok(handler_word));
+        assert!(zktc.cpu.is_user_mode());
+    }
+
+    #[test]
+    fn faults_become_exceptions() {
+        let src = "
+            lih x1, 0x9000@h
+            lw x2, x1, 0
+            .word 0x0013
+            addi x5, x0, 1
+        ";
+        let mut zktc = with_skip_handler(src);
+        assert_eq!(
+            zktc.run(),
+            StopReason::Error(Error::MemoryError(MemoryError::InvalidAddress(0x9000)))
+        );
+
+        zktc.reset();
+        zktc.set_fault_policy(FaultPolicy::Exception);
+        zktc.run();
+        assert_eq!(zktc.read_gr(5), 1);
+        assert_eq!(zktc.read_gr(7), 0x0505 + 0x0405);
+
+        zktc.reset();
+        zktc.cpu.pc = memory::ROM_LOW_ADDRESS + 1;
+        zktc.step().unwrap();
+        assert_eq!(zktc.cpu.psr >> cpu::PSR_CAUSE_SHIFT, 6);
+        zktc.set_fault_policy(FaultPolicy::Stop);
+        zktc.cpu.pc = memory::ROM_LOW_ADDRESS + 1;
+        assert_eq!(
+            zktc.step(),
+            Err(Error::MemoryError(MemoryError::Misaligned(0xb001)))
+        );
+    }
+
+    #[test]
+    fn pc_wraps_at_the_end_of_memory() {
+        let src = r#"
+            reset_pc = 0xfffe
+
+            [[regions]]
+            name = "ram"
+            base = 0x0000
+            size = 0x1000
+            kind = "ram"
+
+            [[regions]]
+            name = "rom"
+            base = 0xf000
+            size = 0x1000
+            kind = "rom"
+        "#;
+        let mut machine =
+            machine::Machine::parse(src, machine::Format::Toml, |_| Ok(Image::default())).unwrap();
+        let mut rom = vec![0; 0xffe];
+        rom.extend(asm::assemble("beq x0, x0, 2", 0xfffe).unwrap());
+        let program = "
+            addi x1, x1, 1
+            beq x0, x0, -4
+        ";
+        machine
+            .set_image(machine::RegionKind::Rom, &Image::relative(rom))
+            .unwrap();
+        machine
+            .set_image(
+                machine::RegionKind::Ram,
+                &Image::relative(asm::assemble(program, 0).unwrap()),
+            )
+            .unwrap();
+        let mut zktc = Zktc::from_machine(&machine).unwrap();
+        assert_eq!(zktc.run_steps(6), None);
+        assert_eq!((zktc.cpu.pc, zktc.read_gr(1)), (0xfffe, 2));
+    }
+
+    #[test]
+    fn machine_description() {
+        let src = r#"
+            [[regions]]
+            name = "ram"
+            base = 0x0000
+            size = 0x1000
+            kind = "ram"
+
+            [[regions]]
+            name = "flash"
+            base = 0x4000
+            size = 0x1000
+            kind = "rom"
+        "#;
+        let program = "
+            addi x1, x0, 1
+            sw x1, x0, 14
+            sw x1, x2, 0
+            addi x5, x0, 1
+        ";
+        let mut machine =
+            machine::Machine::parse(src, machine::Format::Toml, |_| Ok(Image::default())).unwrap();
+        machine
+            .set_image(
+                machine::RegionKind::Rom,
+                &Image::relative(asm::assemble(program, 0x4000).unwrap()),
+            )
+            .unwrap();
+        let mut zktc = Zktc::from_machine(&machine).unwrap();
+        assert_eq!(zktc.cpu.pc, 0x4000);
+        assert_eq!(
+            zktc.read_memory(0xb000, false),
+            Err(Error::MemoryError(MemoryError::InvalidAddress(0xb000)))
+        );
+
+        // the rom is not writable
+        zktc.cpu.gr[2] = 0x4000;
+        zktc.step().unwrap();
+        zktc.step().unwrap();
+        assert_eq!(
+            zktc.step(),
+            Err(Error::MemoryError(MemoryError::NotWritable(0x4000)))
+        );
+
+        zktc.reset();
+        assert_eq!(zktc.cpu.pc, 0x4000);
+    }
+
+    #[test]
+    fn misaligned_accesses() {
+        let src = "
+            addi x1, x0, 3
+            lw x2, x1, 0
+            push x2
+            addi x5, x0, 1
+        ";
+        let mut zktc = with_skip_handler(src);
+        let ram = asm::assemble(SKIP_HANDLER, 0).unwrap();
+        assert_eq!(
+            zktc.run(),
+            StopReason::Error(Error::MemoryError(MemoryError::Misaligned(3)))
+        );
+
+        zktc.reset();
+        zktc.set_fault_policy(FaultPolicy::Exception);
+        zktc.cpu.sp = 0x0101;
+        zktc.run();
+        assert_eq!(zktc.read_gr(5), 1);
+        assert_eq!(zktc.read_gr(6) >> cpu::PSR_CAUSE_SHIFT, 6);
+        // push faulted too
+        assert_eq!(zktc.cpu.sp, 0x0101);
+
+        zktc.reset();
+        zktc.memory.set_alignment(memory::Alignment::Split);
+        zktc.cpu.sp = 0x0101;
+        zktc.run();
+        let split = u16::from_le_bytes([ram[3], ram[4]]);
+        assert_eq!(zktc.read_gr(2), split);
+        assert_eq!(zktc.read_memory(0x00ff, false), Ok(split));
+
+        zktc.reset();
+        zktc.memory.set_alignment(memory::Alignment::ForceAlign);
+        zktc.cpu.sp = 0x0101;
+        zktc.run();
+        let aligned = u16::from_le_bytes([ram[2], ram[3]]);
+        assert_eq!(zktc.read_gr(2), aligned);
+        assert_eq!(zktc.read_memory(0x00fe, false), Ok(aligned));
+    }
+
+    #[test]
+    fn rom_write_policies() {
+        let src = "
+            lil x1, 0xb000@l
+            lih x2, 0xb000@h
+            or x1, x2
+            addi x3, x0, 7
+            sw x3, x1, 0
+            addi x5, x0, 1
+        ";
+        let rom = asm::assemble(src, memory::ROM_LOW_ADDRESS).unwrap();
+        let mut zktc = with_skip_handler(src);
+        assert_eq!(
+            zktc.run(),
+            StopReason::Error(Error::MemoryError(MemoryError::NotWritable(0xb000)))
+        );
+
+        for policy in [WritePolicy::Ignore, WritePolicy::Warn] {
+            zktc.reset();
+            zktc.set_write_policy(policy);
+            zktc.run();
+            assert_eq!(zktc.read_gr(5), 1);
+            assert_eq!(
+                zktc.read_memory(0xb000, false),
+                Ok(rom[0] as u16 | (rom[1] as u16) << 8)
+            );
+        }
+
+        zktc.reset();
+        zktc.set_write_policy(WritePolicy::Exception);
+        zktc.run();
+        assert_eq!(zktc.read_gr(5), 1);
+        assert_eq!(zktc.read_gr(6) >> cpu::PSR_CAUSE_SHIFT, 5);
+
+        // the debugger can still patch the rom
+        zktc.write_memory(0xb000, 0x1234, false).unwrap();
+        assert_eq!(zktc.read_memory(0xb000, false), Ok(0x1234));
+    }
+
+    // Exceptions return to the next instruction, x6 holds the psr they were taken with and x7
+    // adds them up.
+    const SKIP_HANDLER: &str = "
+        rpsr x6
+        add x7, x6
+        rppc x4
+        addi x4, x4, 2
+        wppc x4
+        rfi
+    ";
+
+    // program is in ROM and SKIP_HANDLER in RAM at the exception vector.
+    fn with_skip_handler(program: &str) -> Zktc {
+        Zktc::new(
+            asm::assemble(program, memory::ROM_LOW_ADDRESS).unwrap(),
+            asm::assemble(SKIP_HANDLER, 0).unwrap(),
+        )
+        .unwrap()
+    }
+
     fn run_test(path: &str) {
         let mut zktc = test_setup(path);
         zktc.run();
@@ -1006,15 +2275,12 @@ mod test {
     }
 
     fn test_setup(path: &str) -> Zktc {
-        let f = std::fs::read_to_string(path).unwrap();
-        let f = f.split_whitespace().collect::<Vec<_>>();
-
-        let mut test_mem: Vec<u8> = vec![];
-        for line in f {
-            let mut hex = hex::decode(line).unwrap();
-            test_mem.append(&mut hex);
-        }
-
-        Zktc::new(test_mem, vec![]).unwrap()
+        let src = std::fs::read_to_string(path).unwrap();
+        let test_mem = asm::assemble(&src, memory::ROM_LOW_ADDRESS).unwrap();
+        let mut machine = Machine::load("test/machine.toml").unwrap();
+        machine
+            .set_image(RegionKind::Rom, &Image::relative(test_mem))
+            .unwrap();
+        Zktc::from_machine(&machine).unwrap()
     }
 }
@@ -0,0 +1,384 @@
+use super::inst::Instruction;
+use super::symbols::Symbols;
+use std::collections::HashMap;
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum AsmError {
+    #[error("line {0} : unknown mnemonic '{1}'")]
+    UnknownMnemonic(usize, String),
+
+    #[error("line {0} : '{1}' takes {2} operands")]
+    WrongOperandCount(usize, String, usize),
+
+    #[error("line {0} : invalid register '{1}'")]
+    InvalidRegister(usize, String),
+
+    #[error("line {0} : invalid immediate '{1}'")]
+    InvalidImmediate(usize, String),
+
+    #[error("line {0} : immediate {1} is out of range")]
+    ImmediateOutOfRange(usize, i32),
+
+    #[error("line {0} : undefined label '{1}'")]
+    UndefinedLabel(usize, String),
+
+    #[error("line {0} : duplicate label '{1}'")]
+    DuplicateLabel(usize, String),
+
+    #[error("line {0} : invalid label '{1}'")]
+    InvalidLabel(usize, String),
+}
+
+struct Statement<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+// Assembles the zktc-asm syntax used in test/asm into little endian bytes placed at base.
+//
+//     label:                 // comment
+//         lil x1, label@l
+//         lih x2, label@h
+//         addi x1, x0, 1
+//         .word 0x0002
+pub fn assemble(src: &str, base: u16) -> Result<Vec<u8>, AsmError> {
+    Ok(assemble_with_symbols(src, base)?.0)
+}
+
+// Also returns the labels, e.g. to write a symbol file.
+pub fn assemble_with_symbols(src: &str, base: u16) -> Result<(Vec<u8>, Symbols), AsmError> {
+    let mut labels: HashMap<&str, u16> = HashMap::new();
+    let mut symbols = Symbols::default();
+    let mut statements = vec![];
+    let mut address = base;
+
+    // first pass : collect labels and their addresses
+    for (i, line) in src.lines().enumerate() {
+        let line_num = i + 1;
+        let mut rest = strip_comment(line).trim();
+        while let Some(pos) = rest.find(':') {
+            let label = rest[..pos].trim();
+            if !is_label(label) {
+                Err(AsmError::InvalidLabel(line_num, label.to_string()))?
+            }
+            if labels.insert(label, address).is_some() {
+                Err(AsmError::DuplicateLabel(line_num, label.to_string()))?
+            }
+            symbols.insert(label, address);
+            rest = rest[pos + 1..].trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = split_statement(rest);
+        let size = if mnemonic == ".word" {
+            operands.len().max(1) as u16 * 2
+        } else {
+            2
+        };
+        statements.push(Statement {
+            line: line_num,
+            mnemonic,
+            operands,
+        });
+        address = address.wrapping_add(size);
+    }
+
+    // second pass : encode
+    let mut bytes = vec![];
+    for statement in statements {
+        for word in encode_statement(&statement, &labels)? {
+            bytes.push((word & 0x00ff) as u8);
+            bytes.push(((word & 0xff00) >> 8) as u8);
+        }
+    }
+    Ok((bytes, symbols))
+}
+
+// Assembles a single instruction without labels, e.g. for patching memory from the debugger.
+pub fn assemble_line(line: &str) -> Result<u16, AsmError> {
+    let (mnemonic, operands) = split_statement(strip_comment(line).trim());
+    let statement = Statement {
+        line: 1,
+        mnemonic,
+        operands,
+    };
+    let words = encode_statement(&statement, &HashMap::new())?;
+    match words.as_slice() {
+        [word] => Ok(*word),
+        _ => Err(AsmError::WrongOperandCount(1, ".word".to_string(), 1)),
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find("//") {
+        Some(pos) => &line[..pos],
+        None => line,
+    }
+}
+
+fn split_statement(statement: &str) -> (&str, Vec<&str>) {
+    let (mnemonic, rest) = match statement.find(char::is_whitespace) {
+        Some(pos) => (&statement[..pos], &statement[pos..]),
+        None => (statement, ""),
+    };
+    let operands = rest
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|o| !o.is_empty())
+        .collect();
+    (mnemonic, operands)
+}
+
+pub fn is_label(label: &str) -> bool {
+    let mut chars = label.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+fn encode_statement(
+    statement: &Statement,
+    labels: &HashMap<&str, u16>,
+) -> Result<Vec<u16>, AsmError> {
+    use Instruction::*;
+
+    let line = statement.line;
+    let mnemonic = statement.mnemonic;
+    let ops = &statement.operands;
+
+    if mnemonic == ".word" {
+        if ops.is_empty() {
+            Err(AsmError::WrongOperandCount(line, mnemonic.to_string(), 1))?
+        }
+        return ops
+            .iter()
+            .map(|op| {
+                let value = parse_value(op, line, labels)?;
+                check_range(value, -0x8000, 0xffff, line)?;
+                Ok(value as u16)
+            })
+            .collect();
+    }
+
+    let count = |n: usize| -> Result<(), AsmError> {
+        if ops.len() != n {
+            Err(AsmError::WrongOperandCount(line, mnemonic.to_string(), n))?
+        }
+        Ok(())
+    };
+    let reg = |i: usize| parse_register(ops[i], line);
+    let uimm = |i: usize, bits: u32| -> Result<u16, AsmError> {
+        let value = parse_value(ops[i], line, labels)?;
+        check_range(value, 0, (1 << bits) - 1, line)?;
+        Ok(value as u16)
+    };
+    let simm = |i: usize, bits: u32| -> Result<i16, AsmError> {
+        let value = parse_value(ops[i], line, labels)?;
+        check_range(value, -(1 << (bits - 1)), (1 << (bits - 1)) - 1, line)?;
+        Ok(value as i16)
+    };
+
+    let inst = match mnemonic {
+        "mov" | "add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" => {
+            count(2)?;
+            let (rd, rs) = (reg(0)?, reg(1)?);
+            match mnemonic {
+                "mov" => Mov { rd, rs },
+                "add" => Add { rd, rs },
+                "sub" => Sub { rd, rs },
+                "and" => And { rd, rs },
+                "or" => Or { rd, rs },
+                "xor" => Xor { rd, rs },
+                "sll" => Sll { rd, rs },
+                "srl" => Srl { rd, rs },
+                _ => Sra { rd, rs },
+            }
+        }
+        "addi" | "subi" => {
+            count(3)?;
+            let (rd, rs, imm) = (reg(0)?, reg(1)?, uimm(2, 5)?);
+            match mnemonic {
+                "addi" => Addi { rd, rs, imm },
+                _ => Subi { rd, rs, imm },
+            }
+        }
+        "beq" | "bnq" | "blt" | "bge" | "bltu" | "bgeu" | "jalr" | "lh" | "lhu" | "lw" | "sh"
+        | "sw" => {
+            count(3)?;
+            let (rd, rs, imm) = (reg(0)?, reg(1)?, simm(2, 5)?);
+            match mnemonic {
+                "beq" => Beq { rd, rs, imm },
+                "bnq" => Bnq { rd, rs, imm },
+                "blt" => Blt { rd, rs, imm },
+                "bge" => Bge { rd, rs, imm },
+                "bltu" => Bltu { rd, rs, imm },
+                "bgeu" => Bgeu { rd, rs, imm },
+                "jalr" => Jalr { rd, rs, imm },
+                "lh" => Lh { rd, rs, imm },
+                "lhu" => Lhu { rd, rs, imm },
+                "lw" => Lw { rd, rs, imm },
+                "sh" => Sh { rd, rs, imm },
+                _ => Sw { rd, rs, imm },
+            }
+        }
+        "jal" => {
+            count(2)?;
+            Jal {
+                rd: reg(0)?,
+                imm: simm(1, 8)?,
+            }
+        }
+        "lil" | "lih" => {
+            count(2)?;
+            let (rd, imm) = (reg(0)?, uimm(1, 8)?);
+            match mnemonic {
+                "lil" => Lil { rd, imm },
+                _ => Lih { rd, imm },
+            }
+        }
+        "push" | "pop" | "rpc" | "rsp" | "rpsr" | "rtlr" | "rthr" | "rppc" | "rppsr" | "wsp"
+        | "wpsr" | "wtlr" | "wthr" | "wppc" | "wppsr" => {
+            count(1)?;
+            let rd = reg(0)?;
+            match mnemonic {
+                "push" => Push { rd },
+                "pop" => Pop { rd },
+                "rpc" => Rpc { rd },
+                "rsp" => Rsp { rd },
+                "rpsr" => Rpsr { rd },
+                "rtlr" => Rtlr { rd },
+                "rthr" => Rthr { rd },
+                "rppc" => Rppc { rd },
+                "rppsr" => Rppsr { rd },
+                "wsp" => Wsp { rd },
+                "wpsr" => Wpsr { rd },
+                "wtlr" => Wtlr { rd },
+                "wthr" => Wthr { rd },
+                "wppc" => Wppc { rd },
+                _ => Wppsr { rd },
+            }
+        }
+        "rfi" | "rtr" | "wtr" | "trap" => {
+            count(0)?;
+            match mnemonic {
+                "rfi" => Rfi,
+                "rtr" => Rtr,
+                "wtr" => Wtr,
+                _ => Trap,
+            }
+        }
+        _ => Err(AsmError::UnknownMnemonic(line, mnemonic.to_string()))?,
+    };
+    Ok(vec![inst.encode()])
+}
+
+fn parse_register(op: &str, line: usize) -> Result<u8, AsmError> {
+    match op.strip_prefix('x').map(|n| n.parse::<u8>()) {
+        Some(Ok(num)) if num < 8 => Ok(num),
+        _ => Err(AsmError::InvalidRegister(line, op.to_string())),
+    }
+}
+
+// An immediate is a decimal, 0x hexadecimal or 0b binary number or a label,
+// optionally followed by @l (low byte) or @h (high byte).
+fn parse_value(op: &str, line: usize, labels: &HashMap<&str, u16>) -> Result<i32, AsmError> {
+    if let Some(value) = op.strip_suffix("@l") {
+        return Ok(parse_value(value, line, labels)? & 0x00ff);
+    }
+    if let Some(value) = op.strip_suffix("@h") {
+        return Ok((parse_value(value, line, labels)? & 0xff00) >> 8);
+    }
+
+    let (negative, digits) = match op.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, op),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16)
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i32::from_str_radix(bin, 2)
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<i32>()
+    } else if is_label(op) {
+        return match labels.get(op) {
+            Some(address) => Ok(*address as i32),
+            None => Err(AsmError::UndefinedLabel(line, op.to_string())),
+        };
+    } else {
+        Err(AsmError::InvalidImmediate(line, op.to_string()))?
+    };
+    match value {
+        Ok(value) if negative => Ok(-value),
+        Ok(value) => Ok(value),
+        Err(_) => Err(AsmError::InvalidImmediate(line, op.to_string())),
+    }
+}
+
+fn check_range(value: i32, min: i32, max: i32, line: usize) -> Result<(), AsmError> {
+    if value < min || max < value {
+        Err(AsmError::ImmediateOutOfRange(line, value))?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn assemble_test() {
+        let src = "
+start: // comment
+    lil x7, 0xfffe@l
+    lih x6, end@h
+    addi x1, x0, 1
+    beq x2, x3, -4
+    push x1
+    rfi
+end:
+    .word 0x1234
+";
+        let bytes = assemble(src, 0xb000).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0xf1, 0xfe, 0xd2, 0xb0, 0x21, 0x08, 0x43, 0xe3, 0x3e, 0x08, 0x1f, 0x08, 0x34, 0x12
+            ]
+        );
+    }
+
+    #[test]
+    fn assemble_line_test() {
+        assert_eq!(assemble_line("sw x2, x7, 0"), Ok(0x074e));
+        assert_eq!(assemble_line("trap"), Ok(0xffff));
+    }
+
+    #[test]
+    fn assemble_error_test() {
+        assert_eq!(
+            assemble("foo x1", 0),
+            Err(AsmError::UnknownMnemonic(1, "foo".to_string()))
+        );
+        assert_eq!(
+            assemble("\nadd x1, x8", 0),
+            Err(AsmError::InvalidRegister(2, "x8".to_string()))
+        );
+        assert_eq!(
+            assemble("beq x1, x2, 16", 0),
+            Err(AsmError::ImmediateOutOfRange(1, 16))
+        );
+        assert_eq!(
+            assemble("lil x1, nowhere@l", 0),
+            Err(AsmError::UndefinedLabel(1, "nowhere".to_string()))
+        );
+        assert_eq!(
+            assemble("a:\na:", 0),
+            Err(AsmError::DuplicateLabel(2, "a".to_string()))
+        );
+    }
+}
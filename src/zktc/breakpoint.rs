@@ -0,0 +1,356 @@
+use super::cpu::Cpu;
+use super::expr::{self, Expr, ExprError};
+use super::memory::Memory;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakpointKind {
+    Code,
+    Watch(WatchKind),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub kind: BreakpointKind,
+    pub address: u16,
+    // number of bytes covered, always 1 for code breakpoints
+    pub len: u16,
+    pub enabled: bool,
+    pub hit_count: u32,
+    pub ignore_count: u32,
+    pub condition: Option<Condition>,
+}
+
+// The source text is kept to show the condition back to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub text: String,
+    pub expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, ExprError> {
+        Ok(Condition {
+            text: text.trim().to_string(),
+            expr: expr::parse(text)?,
+        })
+    }
+
+    // A condition that cannot be evaluated stops execution so that the error is not missed.
+    pub fn holds(&self, cpu: &Cpu, memory: &Memory) -> bool {
+        !matches!(self.expr.eval(cpu, memory), Ok(0))
+    }
+}
+
+impl Breakpoint {
+    pub fn contains(&self, address: u16, len: u16) -> bool {
+        let start = self.address as u32;
+        let end = start + self.len as u32;
+        let address = address as u32;
+        address < end && start < address + len as u32
+    }
+}
+
+// A memory access that triggered a watchpoint. Values of byte accesses are zero extended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub pc: u16,
+    pub address: u16,
+    pub half: bool,
+    pub write: bool,
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum BreakpointError {
+    #[error("no breakpoint number {0}")]
+    NotFound(usize),
+
+    #[error("breakpoint {0} is a watchpoint, conditions are only for code breakpoints")]
+    ConditionOnWatchpoint(usize),
+}
+
+#[derive(Debug)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    next_id: usize,
+}
+
+impl Default for Breakpoints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints {
+            list: vec![],
+            next_id: 1,
+        }
+    }
+
+    pub fn add(&mut self, address: u16) -> usize {
+        self.push(BreakpointKind::Code, address, 1)
+    }
+
+    pub fn add_watch(&mut self, kind: WatchKind, address: u16, len: u16) -> usize {
+        self.push(BreakpointKind::Watch(kind), address, len.max(1))
+    }
+
+    fn push(&mut self, kind: BreakpointKind, address: u16, len: u16) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id,
+            kind,
+            address,
+            len,
+            enabled: true,
+            hit_count: 0,
+            ignore_count: 0,
+            condition: None,
+        });
+        id
+    }
+
+    pub fn delete(&mut self, id: usize) -> Result<(), BreakpointError> {
+        let index = self.index(id)?;
+        self.list.remove(index);
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    // Replaces all breakpoints, e.g. with those of a snapshot. New ids continue after them.
+    pub fn restore(&mut self, list: Vec<Breakpoint>) {
+        self.next_id = list.iter().map(|b| b.id + 1).max().unwrap_or(1);
+        self.list = list;
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> Result<(), BreakpointError> {
+        let index = self.index(id)?;
+        self.list[index].enabled = enabled;
+        Ok(())
+    }
+
+    pub fn set_ignore_count(&mut self, id: usize, count: u32) -> Result<(), BreakpointError> {
+        let index = self.index(id)?;
+        self.list[index].ignore_count = count;
+        Ok(())
+    }
+
+    pub fn set_condition(
+        &mut self,
+        id: usize,
+        condition: Option<Condition>,
+    ) -> Result<(), BreakpointError> {
+        let index = self.index(id)?;
+        if condition.is_some() && self.list[index].kind != BreakpointKind::Code {
+            Err(BreakpointError::ConditionOnWatchpoint(id))?
+        }
+        self.list[index].condition = condition;
+        Ok(())
+    }
+
+    pub fn find(&self, kind: BreakpointKind, address: u16, len: u16) -> Option<usize> {
+        self.list
+            .iter()
+            .find(|b| b.kind == kind && b.address == address && b.len == len)
+            .map(|b| b.id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Breakpoint> {
+        self.list.iter().find(|b| b.id == id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn has_watchpoints(&self) -> bool {
+        self.list
+            .iter()
+            .any(|b| b.enabled && matches!(b.kind, BreakpointKind::Watch(_)))
+    }
+
+    // Called when pc reaches a new instruction. Counts a hit on every enabled breakpoint at pc
+    // whose condition holds and returns the first one whose ignore count has run out.
+    pub fn hit(&mut self, cpu: &Cpu, memory: &Memory) -> Option<usize> {
+        self.count_hits(|b| Self::code_matches(b, cpu, memory))
+    }
+
+    // Same as hit, for a memory access of len bytes.
+    pub fn watch_hit(&mut self, address: u16, len: u16, write: bool) -> Option<usize> {
+        self.count_hits(|b| Self::watch_matches(b, address, len, write))
+    }
+
+    // hit and watch_hit without counting, for executing backwards.
+    pub fn peek_hit(&self, cpu: &Cpu, memory: &Memory) -> Option<usize> {
+        self.list
+            .iter()
+            .find(|b| b.enabled && Self::code_matches(b, cpu, memory))
+            .map(|b| b.id)
+    }
+
+    pub fn peek_watch_hit(&self, address: u16, len: u16, write: bool) -> Option<usize> {
+        self.list
+            .iter()
+            .find(|b| b.enabled && Self::watch_matches(b, address, len, write))
+            .map(|b| b.id)
+    }
+
+    fn code_matches(b: &Breakpoint, cpu: &Cpu, memory: &Memory) -> bool {
+        b.kind == BreakpointKind::Code
+            && b.address == cpu.pc
+            && b.condition.as_ref().is_none_or(|c| c.holds(cpu, memory))
+    }
+
+    fn watch_matches(b: &Breakpoint, address: u16, len: u16, write: bool) -> bool {
+        match b.kind {
+            BreakpointKind::Watch(WatchKind::Write) => write && b.contains(address, len),
+            BreakpointKind::Watch(WatchKind::Read) => !write && b.contains(address, len),
+            BreakpointKind::Watch(WatchKind::Access) => b.contains(address, len),
+            BreakpointKind::Code => false,
+        }
+    }
+
+    fn count_hits<F: Fn(&Breakpoint) -> bool>(&mut self, matches: F) -> Option<usize> {
+        let mut stop = None;
+        for b in self.list.iter_mut() {
+            if !b.enabled || !matches(b) {
+                continue;
+            }
+            b.hit_count += 1;
+            if b.ignore_count > 0 {
+                b.ignore_count -= 1;
+            } else if stop.is_none() {
+                stop = Some(b.id);
+            }
+        }
+        stop
+    }
+
+    fn index(&self, id: usize) -> Result<usize, BreakpointError> {
+        self.list
+            .iter()
+            .position(|b| b.id == id)
+            .ok_or(BreakpointError::NotFound(id))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(pc: u16) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.pc = pc;
+        cpu
+    }
+
+    #[test]
+    fn add_and_delete() {
+        let memory = Memory::new(vec![], vec![]).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        assert_eq!(breakpoints.add(0xb000), 1);
+        assert_eq!(breakpoints.add(0xb010), 2);
+        breakpoints.delete(1).unwrap();
+        assert_eq!(breakpoints.delete(1), Err(BreakpointError::NotFound(1)));
+        assert_eq!(breakpoints.add(0xb020), 3);
+        assert_eq!(breakpoints.hit(&at(0xb010), &memory), Some(2));
+        assert_eq!(breakpoints.hit(&at(0xb000), &memory), None);
+    }
+
+    #[test]
+    fn disabled_breakpoint_does_not_stop() {
+        let memory = Memory::new(vec![], vec![]).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0xb000);
+        breakpoints.set_enabled(id, false).unwrap();
+        assert_eq!(breakpoints.hit(&at(0xb000), &memory), None);
+        assert_eq!(breakpoints.get(id).unwrap().hit_count, 0);
+        breakpoints.set_enabled(id, true).unwrap();
+        assert_eq!(breakpoints.hit(&at(0xb000), &memory), Some(id));
+    }
+
+    #[test]
+    fn watchpoint_range() {
+        let memory = Memory::new(vec![], vec![]).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let write = breakpoints.add_watch(WatchKind::Write, 0x8000, 4);
+        let read = breakpoints.add_watch(WatchKind::Read, 0x8004, 1);
+        let access = breakpoints.add_watch(WatchKind::Access, 0x8010, 2);
+        assert_eq!(breakpoints.watch_hit(0x7ffe, 2, true), None);
+        assert_eq!(breakpoints.watch_hit(0x7fff, 2, true), Some(write));
+        assert_eq!(breakpoints.watch_hit(0x8003, 1, false), None);
+        assert_eq!(breakpoints.watch_hit(0x8003, 2, false), Some(read));
+        assert_eq!(breakpoints.watch_hit(0x8004, 1, true), None);
+        assert_eq!(breakpoints.watch_hit(0x8011, 1, true), Some(access));
+        assert_eq!(breakpoints.watch_hit(0x8011, 1, false), Some(access));
+        assert_eq!(breakpoints.hit(&at(0x8000), &memory), None);
+    }
+
+    #[test]
+    fn ignore_count() {
+        let memory = Memory::new(vec![], vec![]).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0xb000);
+        breakpoints.set_ignore_count(id, 2).unwrap();
+        assert_eq!(breakpoints.hit(&at(0xb000), &memory), None);
+        assert_eq!(breakpoints.hit(&at(0xb000), &memory), None);
+        assert_eq!(breakpoints.hit(&at(0xb000), &memory), Some(id));
+        assert_eq!(breakpoints.get(id).unwrap().hit_count, 3);
+    }
+
+    #[test]
+    fn peek_does_not_count() {
+        let memory = Memory::new(vec![], vec![]).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0xb000);
+        let watch = breakpoints.add_watch(WatchKind::Write, 0x8000, 2);
+        breakpoints.set_ignore_count(id, 1).unwrap();
+        assert_eq!(breakpoints.peek_hit(&at(0xb000), &memory), Some(id));
+        assert_eq!(breakpoints.peek_watch_hit(0x8001, 1, true), Some(watch));
+        assert_eq!(breakpoints.peek_watch_hit(0x8001, 1, false), None);
+        let b = breakpoints.get(id).unwrap();
+        assert_eq!((b.hit_count, b.ignore_count), (0, 1));
+    }
+
+    #[test]
+    fn conditional_breakpoint() {
+        let memory = Memory::new(vec![], vec![]).unwrap();
+        let mut breakpoints = Breakpoints::new();
+        let id = breakpoints.add(0xb000);
+        let condition = Condition::parse("x1 == 2").unwrap();
+        breakpoints.set_condition(id, Some(condition)).unwrap();
+        let mut cpu = at(0xb000);
+        assert_eq!(breakpoints.hit(&cpu, &memory), None);
+        cpu.set_gr(1, 2);
+        assert_eq!(breakpoints.hit(&cpu, &memory), Some(id));
+        assert_eq!(breakpoints.get(id).unwrap().hit_count, 1);
+
+        let watch = breakpoints.add_watch(WatchKind::Write, 0x8000, 2);
+        let condition = Condition::parse("x1 == 2").unwrap();
+        assert_eq!(
+            breakpoints.set_condition(watch, Some(condition)),
+            Err(BreakpointError::ConditionOnWatchpoint(watch))
+        );
+        assert_eq!(breakpoints.set_condition(watch, None), Ok(()));
+    }
+}
@@ -0,0 +1,624 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Something mapped into the address space. Offsets are relative to the base address of the
+// mapping and word accesses are little endian. read8 and read16 are the accesses made by the
+// cpu and may have side effects, peek8 and peek16 are for the debugger and must not.
+pub trait Device: Send {
+    fn read8(&mut self, offset: u16) -> u8 {
+        self.peek8(offset)
+    }
+
+    fn write8(&mut self, offset: u16, data: u8);
+
+    fn read16(&mut self, offset: u16) -> u16 {
+        u16::from_le_bytes([self.read8(offset), self.read8(offset + 1)])
+    }
+
+    fn write16(&mut self, offset: u16, data: u16) {
+        let [low, high] = data.to_le_bytes();
+        self.write8(offset, low);
+        self.write8(offset + 1, high);
+    }
+
+    fn peek8(&self, offset: u16) -> u8;
+
+    fn peek16(&self, offset: u16) -> u16 {
+        u16::from_le_bytes([self.peek8(offset), self.peek8(offset + 1)])
+    }
+
+    // Called once per executed instruction.
+    fn tick(&mut self) {}
+
+    fn reset(&mut self) {}
+
+    // Whether the device requests an interrupt. The request goes to the cpu unless the device
+    // is connected to a line of an interrupt controller.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Interrupt lines raised once per instruction, only interrupt controllers use them.
+    fn raise(&mut self, _lines: u16) {}
+
+    // Device state kept in snapshots.
+    fn save_state(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Plain memory has no side effects and its state is its bytes. Steps are undone by poking
+    // the old bytes back into memory and loading the saved state of the other devices.
+    fn is_memory(&self) -> bool {
+        false
+    }
+}
+
+// Saved states of the devices other than plain memory, by mapping index.
+pub type DeviceStates = Vec<(usize, Vec<u8>)>;
+
+// Bytes used by saved device states, including the vector holding them.
+pub fn states_size(states: &DeviceStates) -> usize {
+    states
+        .iter()
+        .map(|(_, state)| std::mem::size_of::<(usize, Vec<u8>)>() + state.len())
+        .sum()
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum BusError {
+    #[error("address 0x{0:04x} is not mapped")]
+    Unmapped(u16),
+
+    #[error("device '{0}' overlaps device '{1}'")]
+    Overlap(String, String),
+
+    #[error("device '{0}' does not fit in the address space")]
+    OutOfRange(String),
+
+    #[error("no device '{0}'")]
+    NoDevice(String),
+
+    #[error("interrupt line {0} does not exist")]
+    InvalidLine(u8),
+}
+
+// What instructions may do with a mapping, the debugger ignores them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const ALL: Permissions = Permissions {
+        read: true,
+        write: true,
+        execute: true,
+    };
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+// "rx", "rw-", ...
+impl FromStr for Permissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut permissions = Permissions {
+            read: false,
+            write: false,
+            execute: false,
+        };
+        for c in s.chars() {
+            match c {
+                'r' => permissions.read = true,
+                'w' => permissions.write = true,
+                'x' => permissions.execute = true,
+                '-' => {}
+                _ => Err(format!("unknown permission '{}' in '{}'", c, s))?,
+            }
+        }
+        Ok(permissions)
+    }
+}
+
+pub struct Mapping {
+    pub name: String,
+    pub base: u16,
+    pub size: u16,
+    // interrupt controller line the device requests interrupts on
+    pub irq_line: Option<u8>,
+    pub permissions: Permissions,
+    device: Box<dyn Device>,
+}
+
+impl Mapping {
+    pub fn device(&self) -> &dyn Device {
+        self.device.as_ref()
+    }
+
+    // last address of the mapping
+    pub fn end(&self) -> u16 {
+        self.base + (self.size - 1)
+    }
+
+    fn offset(&self, address: u16) -> Option<u16> {
+        address
+            .checked_sub(self.base)
+            .filter(|&offset| offset < self.size)
+    }
+}
+
+// Routes accesses to the device mapped at the address. A word access crossing the end of a
+// mapping is split into two byte accesses.
+#[derive(Default)]
+pub struct Bus {
+    mappings: Vec<Mapping>,
+    // states of the devices before their first access since record_devices
+    journal: Option<DeviceStates>,
+}
+
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.mappings
+                    .iter()
+                    .map(|m| format!("{} 0x{:04x}-0x{:04x}", m.name, m.base, m.end())),
+            )
+            .finish()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            mappings: vec![],
+            journal: None,
+        }
+    }
+
+    pub fn map(
+        &mut self,
+        name: &str,
+        base: u16,
+        size: u16,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        if size == 0 || base as u32 + size as u32 > 0x10000 {
+            Err(BusError::OutOfRange(name.to_string()))?
+        }
+        let mapping = Mapping {
+            name: name.to_string(),
+            base,
+            size,
+            irq_line: None,
+            permissions: Permissions::ALL,
+            device,
+        };
+        if let Some(other) = self
+            .mappings
+            .iter()
+            .find(|m| m.base <= mapping.end() && mapping.base <= m.end())
+        {
+            Err(BusError::Overlap(name.to_string(), other.name.clone()))?
+        }
+        self.mappings.push(mapping);
+        Ok(())
+    }
+
+    pub fn mappings(&self) -> impl Iterator<Item = &Mapping> {
+        self.mappings.iter()
+    }
+
+    pub fn device(&self, name: &str) -> Option<&dyn Device> {
+        self.mappings
+            .iter()
+            .find(|m| m.name == name)
+            .map(|m| m.device.as_ref())
+    }
+
+    pub fn device_mut(&mut self, name: &str) -> Option<&mut (dyn Device + 'static)> {
+        self.mappings
+            .iter_mut()
+            .find(|m| m.name == name)
+            .map(|m| m.device.as_mut())
+    }
+
+    pub fn read8(&mut self, address: u16) -> Result<u8, BusError> {
+        let (index, offset) = self.find(address, 1)?;
+        self.touch(index);
+        Ok(self.mappings[index].device.read8(offset))
+    }
+
+    pub fn read16(&mut self, address: u16) -> Result<u16, BusError> {
+        match self.find(address, 2) {
+            Ok((index, offset)) => {
+                self.touch(index);
+                Ok(self.mappings[index].device.read16(offset))
+            }
+            Err(_) => {
+                let high = self.split(address)?;
+                Ok(u16::from_le_bytes([
+                    self.read8(address)?,
+                    self.read8(high)?,
+                ]))
+            }
+        }
+    }
+
+    pub fn write8(&mut self, address: u16, data: u8) -> Result<(), BusError> {
+        let (index, offset) = self.find(address, 1)?;
+        self.touch(index);
+        self.mappings[index].device.write8(offset, data);
+        Ok(())
+    }
+
+    pub fn write16(&mut self, address: u16, data: u16) -> Result<(), BusError> {
+        match self.find(address, 2) {
+            Ok((index, offset)) => {
+                self.touch(index);
+                self.mappings[index].device.write16(offset, data)
+            }
+            Err(_) => {
+                let high = self.split(address)?;
+                let [low_byte, high_byte] = data.to_le_bytes();
+                self.write8(address, low_byte)?;
+                self.write8(high, high_byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn peek8(&self, address: u16) -> Result<u8, BusError> {
+        let (index, offset) = self.find(address, 1)?;
+        Ok(self.mappings[index].device.peek8(offset))
+    }
+
+    pub fn peek16(&self, address: u16) -> Result<u16, BusError> {
+        match self.find(address, 2) {
+            Ok((index, offset)) => Ok(self.mappings[index].device.peek16(offset)),
+            Err(_) => {
+                let high = self.split(address)?;
+                Ok(u16::from_le_bytes([
+                    self.peek8(address)?,
+                    self.peek8(high)?,
+                ]))
+            }
+        }
+    }
+
+    // Writes a byte to plain memory without going through the device, other devices are left
+    // alone as their state is restored by load_devices.
+    pub fn poke8(&mut self, address: u16, data: u8) -> Result<(), BusError> {
+        let (index, offset) = self.find(address, 1)?;
+        let device = &mut self.mappings[index].device;
+        if device.is_memory() {
+            device.write8(offset, data);
+        }
+        Ok(())
+    }
+
+    // Starts saving the state of every device other than plain memory before it is first read,
+    // written or raised, a new journal replaces the one not taken. Ticks are not recorded, so
+    // bytes received by a device the cpu did not access stay received.
+    pub fn record_devices(&mut self, on: bool) {
+        self.journal = on.then(Vec::new);
+    }
+
+    // The states saved since record_devices, which stops recording.
+    pub fn take_devices(&mut self) -> DeviceStates {
+        self.journal.take().unwrap_or_default()
+    }
+
+    pub fn load_devices(&mut self, states: &DeviceStates) {
+        for (index, state) in states {
+            // saved by the same device, so it loads
+            let _ = self.mappings[*index].device.load_state(state);
+        }
+    }
+
+    // Routes interrupt requests of the device to a line of the interrupt controllers.
+    pub fn connect_irq(&mut self, name: &str, line: u8) -> Result<(), BusError> {
+        if line as u32 >= u16::BITS {
+            Err(BusError::InvalidLine(line))?
+        }
+        let mapping = self
+            .mappings
+            .iter_mut()
+            .find(|m| m.name == name)
+            .ok_or_else(|| BusError::NoDevice(name.to_string()))?;
+        mapping.irq_line = Some(line);
+        Ok(())
+    }
+
+    pub fn set_permissions(
+        &mut self,
+        name: &str,
+        permissions: Permissions,
+    ) -> Result<(), BusError> {
+        self.mappings
+            .iter_mut()
+            .find(|m| m.name == name)
+            .ok_or_else(|| BusError::NoDevice(name.to_string()))?
+            .permissions = permissions;
+        Ok(())
+    }
+
+    // Permissions of the mapping at address, None if nothing is mapped there.
+    pub fn permissions(&self, address: u16) -> Option<Permissions> {
+        self.mappings
+            .iter()
+            .find(|m| m.offset(address).is_some())
+            .map(|m| m.permissions)
+    }
+
+    pub fn tick(&mut self) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.tick();
+        }
+        let lines = self
+            .mappings
+            .iter()
+            .filter(|m| m.device.irq())
+            .filter_map(|m| m.irq_line)
+            .fold(0, |lines, line| lines | 1 << line);
+        if lines != 0 {
+            self.raise(lines);
+        }
+    }
+
+    pub fn raise(&mut self, lines: u16) {
+        for index in 0..self.mappings.len() {
+            self.touch(index);
+        }
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.raise(lines);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.device.reset();
+        }
+    }
+
+    // Whether a device not connected to a line requests an interrupt.
+    pub fn irq(&self) -> bool {
+        self.mappings
+            .iter()
+            .any(|m| m.irq_line.is_none() && m.device.irq())
+    }
+
+    // A word access at the last byte of a mapping is made as two byte accesses, after checking
+    // that both bytes are mapped. Returns the address of the high byte, which wraps at 0xffff.
+    fn split(&self, address: u16) -> Result<u16, BusError> {
+        let high = address.wrapping_add(1);
+        self.find(address, 1)?;
+        self.find(high, 1)?;
+        Ok(high)
+    }
+
+    fn touch(&mut self, index: usize) {
+        let Some(journal) = self.journal.as_mut() else {
+            return;
+        };
+        let device = &self.mappings[index].device;
+        if !device.is_memory() && journal.iter().all(|(i, _)| *i != index) {
+            journal.push((index, device.save_state()));
+        }
+    }
+
+    fn find(&self, address: u16, len: u16) -> Result<(usize, u16), BusError> {
+        self.mappings
+            .iter()
+            .enumerate()
+            .find_map(|(index, m)| {
+                let offset = m.offset(address)?;
+                (offset as u32 + len as u32 <= m.size as u32).then_some((index, offset))
+            })
+            .ok_or(BusError::Unmapped(address))
+    }
+}
+
+// Plain memory, used for both ROM and RAM.
+pub struct Ram {
+    bytes: Vec<u8>,
+}
+
+impl Ram {
+    // image is placed at offset 0 and the rest is zero
+    pub fn new(size: u16, image: &[u8]) -> Self {
+        let mut bytes = image.to_vec();
+        bytes.resize(size as usize, 0);
+        Ram { bytes }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Device for Ram {
+    fn write8(&mut self, offset: u16, data: u8) {
+        self.bytes[offset as usize] = data;
+    }
+
+    fn peek8(&self, offset: u16) -> u8 {
+        self.bytes[offset as usize]
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != self.bytes.len() {
+            Err(format!(
+                "{} bytes for {} bytes of memory",
+                state.len(),
+                self.bytes.len()
+            ))?
+        }
+        self.bytes.copy_from_slice(state);
+        Ok(())
+    }
+
+    fn is_memory(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Counts reads to check that peeks have no side effects.
+    struct Counter {
+        reads: u8,
+    }
+
+    impl Device for Counter {
+        fn read8(&mut self, _offset: u16) -> u8 {
+            self.reads += 1;
+            self.reads
+        }
+
+        fn write8(&mut self, _offset: u16, data: u8) {
+            self.reads = data;
+        }
+
+        fn peek8(&self, _offset: u16) -> u8 {
+            self.reads
+        }
+
+        fn reset(&mut self) {
+            self.reads = 0;
+        }
+
+        fn save_state(&self) -> Vec<u8> {
+            vec![self.reads]
+        }
+    }
+
+    #[test]
+    fn routes_to_devices() {
+        let mut bus = Bus::new();
+        bus.map("ram", 0x0000, 0x10, Box::new(Ram::new(0x10, &[0x34, 0x12])))
+            .unwrap();
+        bus.map("counter", 0x9000, 2, Box::new(Counter { reads: 0 }))
+            .unwrap();
+
+        assert_eq!(bus.read16(0x0000), Ok(0x1234));
+        bus.write16(0x000f - 1, 0xbeef).unwrap();
+        assert_eq!(bus.peek8(0x000f), Ok(0xbe));
+        assert_eq!(bus.read16(0x000f), Err(BusError::Unmapped(0x0010)));
+        assert_eq!(bus.read8(0x0010), Err(BusError::Unmapped(0x0010)));
+
+        assert_eq!(bus.read8(0x9001), Ok(1));
+        assert_eq!(bus.peek8(0x9001), Ok(1));
+        assert_eq!(bus.read16(0x9000), Ok(0x0302));
+        bus.reset();
+        assert_eq!(bus.peek8(0x9000), Ok(0));
+    }
+
+    #[test]
+    fn records_accessed_devices() {
+        let mut bus = Bus::new();
+        bus.map("ram", 0x0000, 0x10, Box::new(Ram::new(0x10, &[])))
+            .unwrap();
+        bus.map("counter", 0x9000, 2, Box::new(Counter { reads: 5 }))
+            .unwrap();
+
+        bus.record_devices(true);
+        bus.write8(0x0000, 1).unwrap();
+        bus.peek8(0x9000).unwrap();
+        assert_eq!(bus.take_devices(), vec![]);
+
+        bus.record_devices(true);
+        bus.read8(0x9000).unwrap();
+        bus.read8(0x9000).unwrap();
+        assert_eq!(bus.take_devices(), vec![(1, vec![5])]);
+        bus.read8(0x9000).unwrap();
+        assert_eq!(bus.take_devices(), vec![]);
+    }
+
+    #[test]
+    fn rejects_overlapping_devices() {
+        let mut bus = Bus::new();
+        bus.map("a", 0x8000, 0x100, Box::new(Ram::new(0x100, &[])))
+            .unwrap();
+        assert_eq!(
+            bus.map("b", 0x80ff, 1, Box::new(Ram::new(1, &[]))),
+            Err(BusError::Overlap("b".to_string(), "a".to_string()))
+        );
+        assert_eq!(
+            bus.map("c", 0xffff, 2, Box::new(Ram::new(2, &[]))),
+            Err(BusError::OutOfRange("c".to_string()))
+        );
+        bus.map("d", 0xffff, 1, Box::new(Ram::new(1, &[]))).unwrap();
+        assert_eq!(
+            bus.mappings().map(|m| m.end()).collect::<Vec<_>>(),
+            vec![0x80ff, 0xffff]
+        );
+    }
+
+    #[test]
+    fn splits_words_across_mappings() {
+        let mut bus = Bus::new();
+        bus.map("low", 0x0000, 0x10, Box::new(Ram::new(0x10, &[0x11])))
+            .unwrap();
+        bus.map("mid", 0x0010, 0x10, Box::new(Ram::new(0x10, &[0x22])))
+            .unwrap();
+        bus.map("top", 0xfff0, 0x10, Box::new(Ram::new(0x10, &[])))
+            .unwrap();
+
+        bus.write16(0x000f, 0x3344).unwrap();
+        assert_eq!((bus.peek8(0x000f), bus.peek8(0x0010)), (Ok(0x44), Ok(0x33)));
+        assert_eq!(bus.read16(0x000f), Ok(0x3344));
+        assert_eq!(bus.peek16(0x000f), Ok(0x3344));
+
+        // the high byte wraps to 0x0000
+        bus.write8(0xffff, 0x55).unwrap();
+        assert_eq!(bus.peek16(0xffff), Ok(0x1155));
+
+        // nothing is written when a byte is unmapped
+        assert_eq!(bus.write16(0x001f, 0xffff), Err(BusError::Unmapped(0x0020)));
+        assert_eq!(bus.peek8(0x001f), Ok(0));
+        assert_eq!(bus.peek16(0xffef), Err(BusError::Unmapped(0xffef)));
+    }
+
+    #[test]
+    fn mapping_permissions() {
+        let mut bus = Bus::new();
+        bus.map("rom", 0xb000, 0x100, Box::new(Ram::new(0x100, &[])))
+            .unwrap();
+        let rx: Permissions = "rx".parse().unwrap();
+        bus.set_permissions("rom", rx).unwrap();
+        assert_eq!(bus.permissions(0xb0ff), Some(rx));
+        assert_eq!(bus.permissions(0xb100), None);
+        assert_eq!(rx.to_string(), "r-x");
+        assert_eq!("r-x".parse(), Ok(rx));
+        assert!("rwz".parse::<Permissions>().is_err());
+        assert_eq!(
+            bus.set_permissions("ram", rx),
+            Err(BusError::NoDevice("ram".to_string()))
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use super::expr::Reg;
+use super::trace::TraceEvent;
+use std::fmt;
+
+pub const DEFAULT_COLUMNS: &str = "pc,rd,rd_value,mem_addr,mem_value";
+
+// What a retired instruction did, as far as the reference trace can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Commit {
+    pub pc: u16,
+    // general purpose register writeback, writes to x0 are not recorded
+    pub rd: Option<(u8, u16)>,
+    pub mem: Option<(u16, u16)>,
+}
+
+impl Commit {
+    pub fn from_event(event: &TraceEvent) -> Self {
+        let rd = event.regs.iter().find_map(|w| match w.reg {
+            Reg::Gr(num) => Some((num, w.new as u16)),
+            _ => None,
+        });
+        let mem = event
+            .memory
+            .filter(|m| m.access.write)
+            .map(|m| (m.access.address, m.new));
+        Commit {
+            pc: event.pc,
+            rd,
+            mem,
+        }
+    }
+}
+
+// Index of the column each field is read from. Fields without a column are not compared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Columns {
+    pub pc: usize,
+    pub rd: Option<usize>,
+    pub rd_value: Option<usize>,
+    pub mem_addr: Option<usize>,
+    pub mem_value: Option<usize>,
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Columns::parse(DEFAULT_COLUMNS).unwrap()
+    }
+}
+
+impl Columns {
+    // "_,pc,rd,rd_value" : one name per column, "_" skips a column
+    pub fn parse(spec: &str) -> Result<Self, CompareError> {
+        let mut pc = None;
+        let mut rd = None;
+        let mut rd_value = None;
+        let mut mem_addr = None;
+        let mut mem_value = None;
+        for (index, name) in spec.split(',').map(str::trim).enumerate() {
+            let field = match name {
+                "_" => continue,
+                "pc" => &mut pc,
+                "rd" => &mut rd,
+                "rd_value" => &mut rd_value,
+                "mem_addr" => &mut mem_addr,
+                "mem_value" => &mut mem_value,
+                _ => Err(CompareError::UnknownColumn(name.to_string()))?,
+            };
+            if field.replace(index).is_some() {
+                Err(CompareError::DuplicateColumn(name.to_string()))?
+            }
+        }
+        if rd.is_some() != rd_value.is_some() {
+            Err(CompareError::UnpairedColumn("rd", "rd_value"))?
+        }
+        if mem_addr.is_some() != mem_value.is_some() {
+            Err(CompareError::UnpairedColumn("mem_addr", "mem_value"))?
+        }
+        Ok(Columns {
+            pc: pc.ok_or(CompareError::MissingPcColumn())?,
+            rd,
+            rd_value,
+            mem_addr,
+            mem_value,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CompareError {
+    #[error("unknown column name '{0}'")]
+    UnknownColumn(String),
+    #[error("column '{0}' is given twice")]
+    DuplicateColumn(String),
+    #[error("column '{0}' needs column '{1}'")]
+    UnpairedColumn(&'static str, &'static str),
+    #[error("no pc column")]
+    MissingPcColumn(),
+    #[error("line {0} : no column {1}")]
+    MissingField(usize, usize),
+    #[error("line {0} : invalid value '{1}'")]
+    InvalidValue(usize, String),
+}
+
+// A commit read from line `line` of the reference trace.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Record {
+    pub line: usize,
+    pub commit: Commit,
+}
+
+// Blank lines and lines starting with '#' are skipped. Columns are separated by commas if the
+// line has any, otherwise by whitespace. Numbers are hex with an optional 0x, "-" or an empty
+// column means nothing was written.
+pub fn parse_reference(src: &str, columns: &Columns) -> Result<Vec<Record>, CompareError> {
+    let mut records = vec![];
+    for (index, line) in src.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = if line.contains(',') {
+            line.split(',').map(str::trim).collect()
+        } else {
+            line.split_whitespace().collect()
+        };
+        let field = |column: usize| -> Result<Option<&str>, CompareError> {
+            match fields.get(column) {
+                Some(&"-") | Some(&"") => Ok(None),
+                Some(field) => Ok(Some(field)),
+                None => Err(CompareError::MissingField(line_number, column + 1)),
+            }
+        };
+        let number = |column: Option<usize>| -> Result<Option<u16>, CompareError> {
+            let Some(column) = column else {
+                return Ok(None);
+            };
+            field(column)?
+                .map(|f| {
+                    u16::from_str_radix(f.trim_start_matches("0x"), 16)
+                        .map_err(|_| CompareError::InvalidValue(line_number, f.to_string()))
+                })
+                .transpose()
+        };
+
+        let pc = number(Some(columns.pc))?
+            .ok_or(CompareError::InvalidValue(line_number, "-".to_string()))?;
+        let rd = match columns.rd {
+            Some(column) => match field(column)? {
+                Some(name) => Some(
+                    name.trim_start_matches('x')
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|&num| num < 8)
+                        .ok_or(CompareError::InvalidValue(line_number, name.to_string()))?,
+                ),
+                None => None,
+            },
+            None => None,
+        };
+        let rd = match (rd, number(columns.rd_value)?) {
+            (Some(0), _) | (None, _) => None,
+            (Some(num), Some(value)) => Some((num, value)),
+            (Some(_), None) => Err(CompareError::InvalidValue(line_number, "-".to_string()))?,
+        };
+        let mem = match (number(columns.mem_addr)?, number(columns.mem_value)?) {
+            (Some(address), Some(value)) => Some((address, value)),
+            (None, None) => None,
+            _ => Err(CompareError::InvalidValue(line_number, "-".to_string()))?,
+        };
+        records.push(Record {
+            line: line_number,
+            commit: Commit { pc, rd, mem },
+        });
+    }
+    Ok(records)
+}
+
+// A retired instruction that does not match the reference. expected is None when the
+// reference trace has already ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub step: u64,
+    pub inst: String,
+    pub actual: Commit,
+    pub expected: Option<Record>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(expected) = self.expected else {
+            return write!(
+                f,
+                "trace diverged at step {} : the reference ended before pc 0x{:04x} {}",
+                self.step, self.actual.pc, self.inst
+            );
+        };
+        writeln!(
+            f,
+            "trace diverged at step {} (reference line {}) : {}",
+            self.step, expected.line, self.inst
+        )?;
+        let rd = |rd: Option<(u8, u16)>| rd.map_or("-".to_string(), |(num, _)| format!("x{}", num));
+        let rd_value =
+            |rd: Option<(u8, u16)>| rd.map_or("-".to_string(), |(_, v)| format!("0x{:04x}", v));
+        let mem_addr =
+            |m: Option<(u16, u16)>| m.map_or("-".to_string(), |(a, _)| format!("0x{:04x}", a));
+        let mem_value =
+            |m: Option<(u16, u16)>| m.map_or("-".to_string(), |(_, v)| format!("0x{:04x}", v));
+        let (actual, expected) = (self.actual, expected.commit);
+        let rows = [
+            (
+                "pc",
+                format!("0x{:04x}", actual.pc),
+                format!("0x{:04x}", expected.pc),
+            ),
+            ("rd", rd(actual.rd), rd(expected.rd)),
+            ("rd value", rd_value(actual.rd), rd_value(expected.rd)),
+            ("mem addr", mem_addr(actual.mem), mem_addr(expected.mem)),
+            ("mem value", mem_value(actual.mem), mem_value(expected.mem)),
+        ];
+        write!(f, "{:<10}{:<10}reference", "", "emulator")?;
+        for (name, actual, expected) in rows {
+            let mark = if actual == expected { "" } else { "<" };
+            let row = format!("{:<10}{:<10}{:<10}{}", name, actual, expected, mark);
+            write!(f, "\n{}", row.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Comparator {
+    columns: Columns,
+    records: Vec<Record>,
+    next: usize,
+}
+
+impl Comparator {
+    pub fn new(records: Vec<Record>, columns: Columns) -> Self {
+        Comparator {
+            columns,
+            records,
+            next: 0,
+        }
+    }
+
+    // Number of reference records not compared yet.
+    pub fn remaining(&self) -> usize {
+        self.records.len().saturating_sub(self.next)
+    }
+
+    // Steps back over the last checked instruction.
+    pub fn rewind(&mut self) {
+        self.next = self.next.saturating_sub(1);
+    }
+
+    pub fn check(&mut self, event: &TraceEvent) -> Option<Divergence> {
+        let mut actual = Commit::from_event(event);
+        let expected = self.records.get(self.next).copied();
+        self.next += 1;
+        // fields the reference does not have are not compared
+        if self.columns.rd.is_none() {
+            actual.rd = None;
+        }
+        if self.columns.mem_addr.is_none() {
+            actual.mem = None;
+        }
+        if expected.is_some_and(|e| e.commit == actual) {
+            return None;
+        }
+        Some(Divergence {
+            step: event.step,
+            inst: event.inst.to_string(),
+            actual,
+            expected,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zktc::inst::Instruction;
+
+    #[test]
+    fn parse_columns() {
+        assert_eq!(
+            Columns::parse("_,pc,_,rd,rd_value"),
+            Ok(Columns {
+                pc: 1,
+                rd: Some(3),
+                rd_value: Some(4),
+                mem_addr: None,
+                mem_value: None,
+            })
+        );
+        assert_eq!(
+            Columns::parse("rd,rd_value"),
+            Err(CompareError::MissingPcColumn())
+        );
+        assert_eq!(
+            Columns::parse("pc,rd"),
+            Err(CompareError::UnpairedColumn("rd", "rd_value"))
+        );
+        assert_eq!(
+            Columns::parse("pc,pc"),
+            Err(CompareError::DuplicateColumn("pc".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_reference_lines() {
+        let src = "
+            # pc rd value addr data
+            b000 x1 0001 - -
+            0xb002, 3, 0x10, ,
+            b004 - - 8000 00ff
+            b006 x0 1234 - -
+        ";
+        let records = parse_reference(src, &Columns::default()).unwrap();
+        let commits: Vec<(usize, Commit)> = records.iter().map(|r| (r.line, r.commit)).collect();
+        assert_eq!(
+            commits,
+            vec![
+                (
+                    3,
+                    Commit {
+                        pc: 0xb000,
+                        rd: Some((1, 1)),
+                        mem: None
+                    }
+                ),
+                (
+                    4,
+                    Commit {
+                        pc: 0xb002,
+                        rd: Some((3, 0x10)),
+                        mem: None
+                    }
+                ),
+                (
+                    5,
+                    Commit {
+                        pc: 0xb004,
+                        rd: None,
+                        mem: Some((0x8000, 0xff))
+                    }
+                ),
+                (
+                    6,
+                    Commit {
+                        pc: 0xb006,
+                        rd: None,
+                        mem: None
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            parse_reference("b000 x9 1 - -", &Columns::default()),
+            Err(CompareError::InvalidValue(1, "x9".to_string()))
+        );
+        assert_eq!(
+            parse_reference("b000 x1", &Columns::default()),
+            Err(CompareError::MissingField(1, 3))
+        );
+    }
+
+    #[test]
+    fn steps_past_the_reference() {
+        let commit = Commit {
+            pc: 0xb000,
+            rd: None,
+            mem: None,
+        };
+        let mut comparator = Comparator::new(vec![Record { line: 1, commit }], Columns::default());
+        let event = |step| TraceEvent {
+            step,
+            pc: 0xb000,
+            word: 0,
+            inst: &Instruction::Trap,
+            regs: &[],
+            memory: None,
+            symbol: None,
+        };
+        assert_eq!(comparator.check(&event(1)), None);
+        assert!(comparator
+            .check(&event(2))
+            .is_some_and(|d| d.expected.is_none()));
+        assert_eq!(comparator.remaining(), 0);
+        comparator.rewind();
+        assert_eq!(comparator.remaining(), 0);
+        comparator.rewind();
+        assert_eq!(comparator.remaining(), 1);
+    }
+
+    #[test]
+    fn divergence_report() {
+        let divergence = Divergence {
+            step: 4,
+            inst: "addi x1 x1 1".to_string(),
+            actual: Commit {
+                pc: 0xb008,
+                rd: Some((1, 2)),
+                mem: None,
+            },
+            expected: Some(Record {
+                line: 7,
+                commit: Commit {
+                    pc: 0xb008,
+                    rd: Some((1, 3)),
+                    mem: None,
+                },
+            }),
+        };
+        assert_eq!(
+            divergence.to_string(),
+            [
+                "trace diverged at step 4 (reference line 7) : addi x1 x1 1",
+                "          emulator  reference",
+                "pc        0xb008    0xb008",
+                "rd        x1        x1",
+                "rd value  0x0002    0x0003    <",
+                "mem addr  -         -",
+                "mem value -         -",
+            ]
+            .join("\n")
+        );
+    }
+}
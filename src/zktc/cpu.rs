@@ -1,6 +1,81 @@
-use super::memory::ROM_LOW_ADDRESS;
+use super::inst::Instruction;
+use super::memory::{Memory, MemoryAccess, MemoryError, ROM_LOW_ADDRESS};
+
+// psr after reset and in the trap handler are the values of the original emulator. The
+// interrupt enable, user mode and cause bits below are not taken from the ZKTC spec, they are
+// this emulator's defaults and a machine description can move them, see PsrLayout.
+pub const PSR_RESET: u16 = 0x8000;
+pub const PSR_TRAP: u16 = 0x0005;
+// Interrupts from devices are taken only while this bit of psr is set. It is clear after
+// reset and on entry to the trap handler.
+pub const PSR_INTERRUPT_ENABLE: u16 = 0x0002;
+// Set while running in user mode, where strict mode restricts what the program can do. It is
+// clear after reset and in the trap handler.
+pub const PSR_USER_MODE: u16 = 0x0008;
+// the 4 bit Exception that entered the trap handler
+pub const PSR_CAUSE_SHIFT: u16 = 8;
+pub const PSR_CAUSE_MASK: u16 = 0x0f00;
+
+// Where the interrupt enable bit, the user mode bit and the cause field are in psr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PsrLayout {
+    pub interrupt_enable: u16,
+    pub user_mode: u16,
+    pub cause_shift: u16,
+}
+
+impl Default for PsrLayout {
+    fn default() -> Self {
+        PsrLayout {
+            interrupt_enable: PSR_INTERRUPT_ENABLE,
+            user_mode: PSR_USER_MODE,
+            cause_shift: PSR_CAUSE_SHIFT,
+        }
+    }
+}
 
-#[derive(Debug)]
+impl PsrLayout {
+    // From bit numbers, None unless the bits and the cause field fit in psr without
+    // overlapping.
+    pub fn from_bits(interrupt_enable: u8, user_mode: u8, cause_shift: u8) -> Option<Self> {
+        if interrupt_enable > 15 || user_mode > 15 || cause_shift > 12 {
+            return None;
+        }
+        let layout = PsrLayout {
+            interrupt_enable: 1 << interrupt_enable,
+            user_mode: 1 << user_mode,
+            cause_shift: cause_shift as u16,
+        };
+        let cause = layout.cause_mask();
+        let overlap = layout.interrupt_enable == layout.user_mode
+            || (layout.interrupt_enable | layout.user_mode) & cause != 0;
+        (!overlap).then_some(layout)
+    }
+
+    pub fn cause_mask(&self) -> u16 {
+        0xf << self.cause_shift
+    }
+
+    // psr on entry to the trap handler, interrupts are disabled and the mode is kernel
+    pub fn trap(&self, exception: Exception) -> u16 {
+        PSR_TRAP & !(self.interrupt_enable | self.user_mode | self.cause_mask())
+            | (exception as u16) << self.cause_shift
+    }
+}
+
+// Why the trap handler was entered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Exception {
+    Trap = 0,
+    Interrupt = 1,
+    PrivilegedInstruction = 2,
+    ProtectionFault = 3,
+    IllegalInstruction = 4,
+    BusError = 5,
+    Misaligned = 6,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Cpu {
     pub pc: u16,
     pub gr: [u16; 8],
@@ -11,21 +86,145 @@ pub struct Cpu {
     pub thr: u16,
     pub ppc: u16,
     pub ppsr: u16,
+    pub psr_layout: PsrLayout,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        Self::with_reset_pc(ROM_LOW_ADDRESS)
+    }
+
+    // The state after reset on a machine starting at reset_pc.
+    pub fn with_reset_pc(reset_pc: u16) -> Self {
         Cpu {
-            pc: ROM_LOW_ADDRESS,
+            pc: reset_pc,
             gr: [0; 8],
             sp: 0,
-            psr: 0x8000,
+            psr: PSR_RESET,
             tr: 0,
             tlr: 0,
             thr: 0,
             ppc: 0,
             ppsr: 0,
+            psr_layout: PsrLayout::default(),
+        }
+    }
+
+    // The memory access inst will make when executed in the current state.
+    pub fn memory_access(&self, inst: &Instruction) -> Option<MemoryAccess> {
+        use Instruction::*;
+
+        let access = |rs: u8, imm: i16, half: bool, write: bool| MemoryAccess {
+            address: self.get_gr(rs).wrapping_add(imm as u16),
+            half,
+            write,
+        };
+        match *inst {
+            Lh { rs, imm, .. } | Lhu { rs, imm, .. } => Some(access(rs, imm, true, false)),
+            Lw { rs, imm, .. } => Some(access(rs, imm, false, false)),
+            Sh { rs, imm, .. } => Some(access(rs, imm, true, true)),
+            Sw { rs, imm, .. } => Some(access(rs, imm, false, true)),
+            Push { .. } => Some(MemoryAccess {
+                address: self.sp.wrapping_sub(2),
+                half: false,
+                write: true,
+            }),
+            Pop { .. } => Some(MemoryAccess {
+                address: self.sp,
+                half: false,
+                write: false,
+            }),
+            _ => None,
+        }
+    }
+
+    // pc must already point to the next instruction, branches and jumps are relative to it.
+    pub fn execute(&mut self, inst: &Instruction, memory: &mut Memory) -> Result<(), MemoryError> {
+        use Instruction::*;
+
+        match *inst {
+            Mov { rd, rs } => self.mov(rd, rs),
+            Add { rd, rs } => self.add(rd, rs),
+            Sub { rd, rs } => self.sub(rd, rs),
+            And { rd, rs } => self.and(rd, rs),
+            Or { rd, rs } => self.or(rd, rs),
+            Xor { rd, rs } => self.xor(rd, rs),
+            Sll { rd, rs } => self.sll(rd, rs),
+            Srl { rd, rs } => self.srl(rd, rs),
+            Sra { rd, rs } => self.sra(rd, rs),
+            Addi { rd, rs, imm } => self.addi(rd, rs, imm),
+            Subi { rd, rs, imm } => self.subi(rd, rs, imm),
+            Beq { rd, rs, imm } => self.beq(rd, rs, imm),
+            Bnq { rd, rs, imm } => self.bnq(rd, rs, imm),
+            Blt { rd, rs, imm } => self.blt(rd, rs, imm),
+            Bge { rd, rs, imm } => self.bge(rd, rs, imm),
+            Bltu { rd, rs, imm } => self.bltu(rd, rs, imm),
+            Bgeu { rd, rs, imm } => self.bgeu(rd, rs, imm),
+            Jalr { rd, rs, imm } => self.jalr(rd, rs, imm),
+            Lh { rd, rs, imm } => {
+                let address = self.get_gr(rs).wrapping_add(imm as u16);
+                let data = memory.load(&address, true)?;
+                self.set_gr(rd, data);
+            }
+            Lhu { rd, rs, imm } => {
+                let address = self.get_gr(rs).wrapping_add(imm as u16);
+                let data = memory.load(&address, true)?;
+                let data = data & 0x00ff;
+                self.set_gr(rd, data);
+            }
+            Lw { rd, rs, imm } => {
+                let address = self.get_gr(rs).wrapping_add(imm as u16);
+                let data = memory.load(&address, false)?;
+                self.set_gr(rd, data);
+            }
+            Sh { rd, rs, imm } => {
+                let address = self.get_gr(rs).wrapping_add(imm as u16);
+                let data = self.get_gr(rd);
+                memory.store(&address, data, true)?;
+            }
+            Sw { rd, rs, imm } => {
+                let address = self.get_gr(rs).wrapping_add(imm as u16);
+                let data = self.get_gr(rd);
+                memory.store(&address, data, false)?;
+            }
+            Jal { rd, imm } => self.jal(rd, imm),
+            Lil { rd, imm } => self.lil(rd, imm),
+            Lih { rd, imm } => self.lih(rd, imm),
+            Push { rd } => {
+                let data = self.get_gr(rd);
+                self.sp = self.sp.wrapping_sub(2);
+                memory.store(&self.sp, data, false)?;
+            }
+            Pop { rd } => {
+                let data = memory.load(&self.sp, false)?;
+                self.set_gr(rd, data);
+                self.sp = self.sp.wrapping_add(2);
+            }
+            Rpc { rd } => self.rpc(rd),
+            Rsp { rd } => self.rsp(rd),
+            Rpsr { rd } => self.rpsr(rd),
+            Rtlr { rd } => self.rtlr(rd),
+            Rthr { rd } => self.rthr(rd),
+            Rppc { rd } => self.rppc(rd),
+            Rppsr { rd } => self.rppsr(rd),
+            Wsp { rd } => self.wsp(rd),
+            Wpsr { rd } => self.wpsr(rd),
+            Wtlr { rd } => self.wtlr(rd),
+            Wthr { rd } => self.wthr(rd),
+            Wppc { rd } => self.wppc(rd),
+            Wppsr { rd } => self.wppsr(rd),
+            Rfi => self.rfi(),
+            Rtr => self.rtr(),
+            Wtr => self.wtr(),
+            Trap => self.trap(),
         }
+        Ok(())
     }
 
     // There is no need to check if num is less than 8 because invalid registers cannot be specified by the instruction.
@@ -100,42 +299,42 @@ impl Cpu {
 
     pub fn beq(&mut self, rd: u8, rs: u8, imm: i16) {
         if self.get_gr(rd) == self.get_gr(rs) {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
             self.pc = self.pc.wrapping_add(imm as u16);
         }
     }
 
     pub fn bnq(&mut self, rd: u8, rs: u8, imm: i16) {
         if self.get_gr(rd) != self.get_gr(rs) {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
             self.pc = self.pc.wrapping_add(imm as u16);
         }
     }
 
     pub fn blt(&mut self, rd: u8, rs: u8, imm: i16) {
         if (self.get_gr(rd) as i16) < (self.get_gr(rs) as i16) {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
             self.pc = self.pc.wrapping_add(imm as u16);
         }
     }
 
     pub fn bge(&mut self, rd: u8, rs: u8, imm: i16) {
         if (self.get_gr(rd) as i16) >= (self.get_gr(rs) as i16) {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
             self.pc = self.pc.wrapping_add(imm as u16);
         }
     }
 
     pub fn bltu(&mut self, rd: u8, rs: u8, imm: i16) {
         if self.get_gr(rd) < self.get_gr(rs) {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
             self.pc = self.pc.wrapping_add(imm as u16);
         }
     }
 
     pub fn bgeu(&mut self, rd: u8, rs: u8, imm: i16) {
         if self.get_gr(rd) >= self.get_gr(rs) {
-            self.pc -= 2;
+            self.pc = self.pc.wrapping_sub(2);
             self.pc = self.pc.wrapping_add(imm as u16);
         }
     }
@@ -148,7 +347,7 @@ impl Cpu {
 
     pub fn jal(&mut self, rd: u8, imm: i16) {
         self.set_gr(rd, self.pc);
-        self.pc -= 2;
+        self.pc = self.pc.wrapping_sub(2);
         self.pc = self.pc.wrapping_add(imm as u16);
     }
 
@@ -228,9 +427,27 @@ impl Cpu {
     }
 
     pub fn trap(&mut self) {
-        self.ppc = self.pc;
+        self.exception(Exception::Trap, self.pc);
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.psr & self.psr_layout.interrupt_enable != 0
+    }
+
+    pub fn is_user_mode(&self) -> bool {
+        self.psr & self.psr_layout.user_mode != 0
+    }
+
+    // Enters the handler like trap, returning with rfi resumes at the next instruction.
+    pub fn interrupt(&mut self) {
+        self.exception(Exception::Interrupt, self.pc);
+    }
+
+    // Enters the trap handler, rfi returns to pc.
+    pub fn exception(&mut self, exception: Exception, pc: u16) {
+        self.ppc = pc;
         self.ppsr = self.psr;
-        self.psr = 0x5;
+        self.psr = self.psr_layout.trap(exception);
         self.pc = 0;
     }
 }
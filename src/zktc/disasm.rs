@@ -0,0 +1,67 @@
+use super::inst::decode;
+use super::symbols::Symbols;
+
+// Words that cannot be decoded (including the 0x0000 debug interrupt) are emitted as ".word" so that
+// the output can be fed back to zktc-asm.
+pub fn disassemble_word(word: u16) -> String {
+    match decode(word) {
+        Ok(inst) => format!("{:#}", inst),
+        Err(_) => format!(".word 0x{:04x}", word),
+    }
+}
+
+// The address is followed by its label, if any, e.g. "<fail+0x4>".
+pub fn format_line(address: u16, word: u16, symbols: &Symbols) -> String {
+    let line = format!(
+        "{:<24}// 0x{:04x} : {:016b}",
+        disassemble_word(word),
+        address,
+        word
+    );
+    match symbols.describe(address) {
+        Some(label) => format!("{} <{}>", line, label),
+        None => line,
+    }
+}
+
+// Bytes are little endian, as in memory. An odd trailing byte is padded with zero.
+pub fn disassemble(bytes: &[u8], base: u16, symbols: &Symbols) -> Vec<String> {
+    bytes
+        .chunks(2)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let low = chunk[0] as u16;
+            let high = chunk.get(1).copied().unwrap_or(0) as u16;
+            let address = base.wrapping_add((i * 2) as u16);
+            format_line(address, (high << 8) | low, symbols)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn disassemble_test() {
+        let bytes = [0x21, 0x08, 0x43, 0xe3, 0x00, 0x00];
+        let lines = disassemble(&bytes, 0xb000, &Symbols::default());
+        assert_eq!(
+            lines,
+            vec![
+                "addi x1, x0, 1          // 0xb000 : 0000100000100001",
+                "beq x2, x3, -4          // 0xb002 : 1110001101000011",
+                ".word 0x0000            // 0xb004 : 0000000000000000",
+            ]
+        );
+
+        let symbols = Symbols::parse("loop 0xb002").unwrap();
+        assert_eq!(
+            disassemble(&bytes, 0xb000, &symbols)[1..],
+            [
+                "beq x2, x3, -4          // 0xb002 : 1110001101000011 <loop>",
+                ".word 0x0000            // 0xb004 : 0000000000000000 <loop+0x2>",
+            ]
+        );
+    }
+}
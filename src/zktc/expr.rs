@@ -0,0 +1,402 @@
+use super::cpu::Cpu;
+use super::memory::{Memory, MemoryError};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    Gr(u8),
+    Pc,
+    Sp,
+    Psr,
+    Tr,
+    Tlr,
+    Thr,
+    Ppc,
+    Ppsr,
+}
+
+impl Reg {
+    pub fn read(&self, cpu: &Cpu) -> u32 {
+        match *self {
+            Reg::Gr(num) => cpu.get_gr(num) as u32,
+            Reg::Pc => cpu.pc as u32,
+            Reg::Sp => cpu.sp as u32,
+            Reg::Psr => cpu.psr as u32,
+            Reg::Tr => cpu.tr,
+            Reg::Tlr => cpu.tlr as u32,
+            Reg::Thr => cpu.thr as u32,
+            Reg::Ppc => cpu.ppc as u32,
+            Reg::Ppsr => cpu.ppsr as u32,
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Reg::Gr(num) => write!(f, "x{}", num),
+            Reg::Pc => write!(f, "pc"),
+            Reg::Sp => write!(f, "sp"),
+            Reg::Psr => write!(f, "psr"),
+            Reg::Tr => write!(f, "tr"),
+            Reg::Tlr => write!(f, "tlr"),
+            Reg::Thr => write!(f, "thr"),
+            Reg::Ppc => write!(f, "ppc"),
+            Reg::Ppsr => write!(f, "ppsr"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// Values are unsigned 32 bit so that tr fits. Arithmetic wraps and comparisons are unsigned.
+//
+//     x3 == 0x10 && sp < 0x7f00
+//     [x1 + 2] & 0xff
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(u32),
+    Reg(Reg),
+    Deref(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ExprError {
+    #[error("unexpected '{0}'")]
+    UnexpectedToken(String),
+
+    #[error("unexpected end of expression")]
+    UnexpectedEnd(),
+
+    #[error("invalid number '{0}'")]
+    InvalidNumber(String),
+
+    #[error("unknown register '{0}'")]
+    UnknownRegister(String),
+
+    #[error("division by zero")]
+    DivisionByZero(),
+
+    #[error("{0}")]
+    MemoryError(#[from] MemoryError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Num(u32),
+    Ident(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(s) => write!(f, "{}", s),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+// longest operators first so that "<=" is not read as "<" "="
+const OPS: [&str; 24] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "(", ")", "[", "]",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, ExprError> {
+    let mut tokens = vec![];
+    let mut rest = src.trim_start();
+    while !rest.is_empty() {
+        if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let word = &rest[..end];
+            if word.starts_with(|c: char| c.is_ascii_digit()) {
+                tokens.push(Token::Num(parse_number(word)?));
+            } else {
+                tokens.push(Token::Ident(word.to_string()));
+            }
+            rest = &rest[end..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            Err(ExprError::UnexpectedToken(c.to_string()))?
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Result<u32, ExprError> {
+    let value = if let Some(hex) = word.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(bin) = word.strip_prefix("0b") {
+        u32::from_str_radix(bin, 2)
+    } else {
+        word.parse::<u32>()
+    };
+    value.map_err(|_| ExprError::InvalidNumber(word.to_string()))
+}
+
+fn parse_register(name: &str) -> Option<Reg> {
+    let reg = match name {
+        "pc" => Reg::Pc,
+        "sp" => Reg::Sp,
+        "psr" => Reg::Psr,
+        "tr" => Reg::Tr,
+        "tlr" => Reg::Tlr,
+        "thr" => Reg::Thr,
+        "ppc" => Reg::Ppc,
+        "ppsr" => Reg::Ppsr,
+        _ => match name.strip_prefix('x').map(|n| n.parse::<u8>()) {
+            Some(Ok(num)) if num < 8 => Reg::Gr(num),
+            _ => return None,
+        },
+    };
+    Some(reg)
+}
+
+// binary operators from the lowest precedence
+const LEVELS: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[
+        ("<", BinaryOp::Lt),
+        ("<=", BinaryOp::Le),
+        (">", BinaryOp::Gt),
+        (">=", BinaryOp::Ge),
+    ],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+    ],
+];
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, ExprError> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token.ok_or(ExprError::UnexpectedEnd())
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ExprError> {
+        match self.next()? {
+            Token::Op(o) if o == op => Ok(()),
+            token => Err(ExprError::UnexpectedToken(token.to_string())),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, ExprError> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op(o)) => LEVELS[level]
+                    .iter()
+                    .find(|(s, _)| s == o)
+                    .map(|(_, op)| *op),
+                _ => None,
+            };
+            let Some(op) = op else {
+                return Ok(lhs);
+            };
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        match self.next()? {
+            Token::Num(n) => Ok(Expr::Num(n)),
+            Token::Ident(name) => parse_register(&name)
+                .map(Expr::Reg)
+                .ok_or(ExprError::UnknownRegister(name)),
+            Token::Op("-") => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))),
+            Token::Op("!") => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+            Token::Op("~") => Ok(Expr::Unary(UnaryOp::BitNot, Box::new(self.unary()?))),
+            Token::Op("(") => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Op("[") => {
+                let expr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Deref(Box::new(expr)))
+            }
+            token => Err(ExprError::UnexpectedToken(token.to_string())),
+        }
+    }
+}
+
+pub fn parse(src: &str) -> Result<Expr, ExprError> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let expr = parser.binary(0)?;
+    match parser.peek() {
+        Some(token) => Err(ExprError::UnexpectedToken(token.to_string())),
+        None => Ok(expr),
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, cpu: &Cpu, memory: &Memory) -> Result<u32, ExprError> {
+        let value = match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(reg) => reg.read(cpu),
+            Expr::Deref(address) => {
+                let address = address.eval(cpu, memory)? as u16;
+                memory.read_from_memory(&address, false)? as u32
+            }
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(cpu, memory)?;
+                match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as u32,
+                    UnaryOp::BitNot => !value,
+                }
+            }
+            Expr::Binary(BinaryOp::Or, lhs, rhs) => {
+                (lhs.eval(cpu, memory)? != 0 || rhs.eval(cpu, memory)? != 0) as u32
+            }
+            Expr::Binary(BinaryOp::And, lhs, rhs) => {
+                (lhs.eval(cpu, memory)? != 0 && rhs.eval(cpu, memory)? != 0) as u32
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu, memory)?;
+                let rhs = rhs.eval(cpu, memory)?;
+                match op {
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Eq => (lhs == rhs) as u32,
+                    BinaryOp::Ne => (lhs != rhs) as u32,
+                    BinaryOp::Lt => (lhs < rhs) as u32,
+                    BinaryOp::Le => (lhs <= rhs) as u32,
+                    BinaryOp::Gt => (lhs > rhs) as u32,
+                    BinaryOp::Ge => (lhs >= rhs) as u32,
+                    BinaryOp::Shl => lhs.checked_shl(rhs).unwrap_or(0),
+                    BinaryOp::Shr => lhs.checked_shr(rhs).unwrap_or(0),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or(ExprError::DivisionByZero())?,
+                    BinaryOp::Rem => lhs.checked_rem(rhs).ok_or(ExprError::DivisionByZero())?,
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                }
+            }
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(src: &str) -> Result<u32, ExprError> {
+        let mut cpu = Cpu::new();
+        cpu.set_gr(3, 0x10);
+        cpu.sp = 0x7000;
+        let mut memory = Memory::new(vec![], vec![]).unwrap();
+        memory.write_to_memory(&0x10, 0xbeef, false).unwrap();
+        parse(src)?.eval(&cpu, &memory)
+    }
+
+    #[test]
+    fn precedence_test() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("1 << 2 + 1"), Ok(8));
+        assert_eq!(eval("0xf0 | 0x0f & 0x3"), Ok(0xf3));
+        assert_eq!(eval("1 < 2 == 1"), Ok(1));
+        assert_eq!(eval("-1"), Ok(0xffffffff));
+        assert_eq!(eval("!0 && ~0"), Ok(1));
+    }
+
+    #[test]
+    fn register_and_memory_test() {
+        assert_eq!(eval("x3 == 0x10 && sp < 0x7f00"), Ok(1));
+        assert_eq!(eval("pc"), Ok(0xb000));
+        assert_eq!(eval("[x3]"), Ok(0xbeef));
+        assert_eq!(eval("[x3] & 0xff"), Ok(0xef));
+        assert_eq!(
+            eval("x0 || [0x9000]"),
+            Err(MemoryError::InvalidAddress(0x9000).into())
+        );
+        assert_eq!(eval("1 || [0x9000]"), Ok(1));
+    }
+
+    #[test]
+    fn error_test() {
+        assert_eq!(
+            eval("x8"),
+            Err(ExprError::UnknownRegister("x8".to_string()))
+        );
+        assert_eq!(eval("1 +"), Err(ExprError::UnexpectedEnd()));
+        assert_eq!(eval("(1"), Err(ExprError::UnexpectedEnd()));
+        assert_eq!(
+            eval("1 2"),
+            Err(ExprError::UnexpectedToken("2".to_string()))
+        );
+        assert_eq!(
+            eval("0xz"),
+            Err(ExprError::InvalidNumber("0xz".to_string()))
+        );
+        assert_eq!(eval("1 / 0"), Err(ExprError::DivisionByZero()));
+    }
+}
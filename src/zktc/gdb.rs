@@ -0,0 +1,588 @@
+use super::breakpoint::{BreakpointKind, WatchKind};
+use super::{Error, StopReason, Zktc};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Register numbers follow the order of TARGET_XML.
+// 0-7 : x0-x7, 8 : pc, 9 : sp, 10 : psr, 11 : tr, 12 : tlr, 13 : thr, 14 : ppc, 15 : ppsr
+const REG_COUNT: usize = 16;
+const REG_TR: usize = 11;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.zktc.core">
+    <reg name="x0" bitsize="16" type="int" regnum="0"/>
+    <reg name="x1" bitsize="16" type="int"/>
+    <reg name="x2" bitsize="16" type="int"/>
+    <reg name="x3" bitsize="16" type="int"/>
+    <reg name="x4" bitsize="16" type="int"/>
+    <reg name="x5" bitsize="16" type="int"/>
+    <reg name="x6" bitsize="16" type="int"/>
+    <reg name="x7" bitsize="16" type="int"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="psr" bitsize="16" type="int"/>
+    <reg name="tr" bitsize="32" type="int"/>
+    <reg name="tlr" bitsize="16" type="int"/>
+    <reg name="thr" bitsize="16" type="int"/>
+    <reg name="ppc" bitsize="16" type="code_ptr"/>
+    <reg name="ppsr" bitsize="16" type="int"/>
+  </feature>
+</target>
+"#;
+
+// Instructions executed between checks for a ctrl-c from gdb while continuing.
+const STEPS_PER_POLL: u64 = 4096;
+
+// Serves a single gdb connection on listener until gdb detaches or kills the target.
+pub fn serve(zktc: &mut Zktc, listener: TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let mut stub = GdbStub {
+        zktc,
+        stream,
+        pending: VecDeque::new(),
+        no_ack: false,
+        closed: false,
+        last_stop: "S05".to_string(),
+    };
+    stub.serve()
+}
+
+struct GdbStub<'a> {
+    zktc: &'a mut Zktc,
+    stream: TcpStream,
+    pending: VecDeque<u8>,
+    no_ack: bool,
+    closed: bool,
+    last_stop: String,
+}
+
+impl GdbStub<'_> {
+    fn serve(&mut self) -> io::Result<()> {
+        while !self.closed {
+            let Some(packet) = self.read_packet()? else {
+                break;
+            };
+            if let Some(reply) = self.handle(&packet)? {
+                self.write_packet(&reply)?;
+            }
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let Some(command) = packet.chars().next() else {
+            return Ok(Some(String::new()));
+        };
+        let args = &packet[1..];
+        let reply = match command {
+            '?' => self.last_stop.clone(),
+            'g' => (0..REG_COUNT).map(|n| self.encode_reg(n)).collect(),
+            'G' => self.write_regs(args),
+            'p' => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REG_COUNT => self.encode_reg(n),
+                _ => "E01".to_string(),
+            },
+            'P' => self.write_reg(args),
+            'm' => self.read_memory(args),
+            'M' => self.write_memory(args),
+            'c' | 's' => {
+                if !args.is_empty() {
+                    match u16::from_str_radix(args, 16) {
+                        Ok(addr) => self.zktc.cpu_mut().pc = addr,
+                        Err(_) => return Ok(Some("E01".to_string())),
+                    }
+                }
+                let reply = if command == 'c' {
+                    self.resume()?
+                } else {
+                    let reason = self.zktc.run_steps(1);
+                    Self::stop_reply(self.zktc, reason)
+                };
+                self.last_stop = reply.clone();
+                reply
+            }
+            // reverse step and reverse continue
+            'b' if args == "s" || args == "c" => {
+                let reply = if args == "c" {
+                    self.resume_back()?
+                } else {
+                    let reason = self.zktc.step_back().err().map(StopReason::Error);
+                    Self::stop_reply(self.zktc, reason)
+                };
+                self.last_stop = reply.clone();
+                reply
+            }
+            'Z' | 'z' => self.breakpoint(command == 'Z', args),
+            'H' => "OK".to_string(),
+            'k' => {
+                self.closed = true;
+                return Ok(None);
+            }
+            'D' => {
+                self.closed = true;
+                "OK".to_string()
+            }
+            'q' | 'Q' => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;ReverseStep+;ReverseContinue+"
+                .to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_pair(args) else {
+                return "E01".to_string();
+            };
+            let xml = TARGET_XML.as_bytes();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            let chunk = String::from_utf8_lossy(&xml[start..end]);
+            if end == xml.len() {
+                format!("l{}", chunk)
+            } else {
+                format!("m{}", chunk)
+            }
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(reason) = self.zktc.run_steps(STEPS_PER_POLL) {
+                return Ok(Self::stop_reply(self.zktc, Some(reason)));
+            }
+            if self.interrupted()? {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    fn resume_back(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(reason) = self.zktc.run_back_steps(STEPS_PER_POLL) {
+                return Ok(Self::stop_reply(self.zktc, Some(reason)));
+            }
+            if self.interrupted()? {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    fn stop_reply(zktc: &Zktc, reason: Option<StopReason>) -> String {
+        match reason {
+            None | Some(StopReason::Breakpoint(_)) => "S05".to_string(),
+            Some(StopReason::Divergence(divergence)) => {
+                // gdb has no way to show it, report on the emulator side
+                eprintln!("{}", divergence);
+                "S05".to_string()
+            }
+            Some(StopReason::Watchpoint(hit)) => {
+                let kind = match zktc.breakpoints().get(hit.id).map(|b| b.kind) {
+                    Some(BreakpointKind::Watch(WatchKind::Read)) => "rwatch",
+                    Some(BreakpointKind::Watch(WatchKind::Access)) => "awatch",
+                    _ => "watch",
+                };
+                format!("T05{}:{:x};", kind, hit.address)
+            }
+            Some(StopReason::Error(Error::UnknownInstruction(_))) => "S04".to_string(),
+            Some(StopReason::Error(Error::MemoryError(_))) => "S0b".to_string(),
+            Some(StopReason::Error(Error::NoHistory())) => "T05replaylog:begin;".to_string(),
+            // the program ended
+            Some(StopReason::Error(Error::DebugInterrupt())) => "W00".to_string(),
+            Some(StopReason::Error(_)) => "S05".to_string(),
+        }
+    }
+
+    // Z0/Z1 : breakpoint, Z2 : write watchpoint, Z3 : read watchpoint, Z4 : access watchpoint
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (
+            u16::from_str_radix(addr, 16),
+            u16::from_str_radix(len.split(';').next().unwrap_or(len), 16),
+        ) else {
+            return "E01".to_string();
+        };
+        let (kind, len) = match kind {
+            "0" | "1" => (BreakpointKind::Code, 1),
+            "2" => (BreakpointKind::Watch(WatchKind::Write), len),
+            "3" => (BreakpointKind::Watch(WatchKind::Read), len),
+            "4" => (BreakpointKind::Watch(WatchKind::Access), len),
+            _ => return String::new(),
+        };
+
+        let breakpoints = self.zktc.breakpoints_mut();
+        let found = breakpoints.find(kind, addr, len);
+        match (insert, found, kind) {
+            (true, None, BreakpointKind::Code) => {
+                breakpoints.add(addr);
+            }
+            (true, None, BreakpointKind::Watch(kind)) => {
+                breakpoints.add_watch(kind, addr, len);
+            }
+            (false, Some(id), _) => {
+                breakpoints.delete(id).unwrap();
+            }
+            _ => {}
+        }
+        "OK".to_string()
+    }
+
+    fn reg(&self, n: usize) -> u32 {
+        let cpu = self.zktc.cpu();
+        match n {
+            0..=7 => cpu.get_gr(n as u8) as u32,
+            8 => cpu.pc as u32,
+            9 => cpu.sp as u32,
+            10 => cpu.psr as u32,
+            11 => cpu.tr,
+            12 => cpu.tlr as u32,
+            13 => cpu.thr as u32,
+            14 => cpu.ppc as u32,
+            _ => cpu.ppsr as u32,
+        }
+    }
+
+    fn set_reg(&mut self, n: usize, value: u32) {
+        let cpu = self.zktc.cpu_mut();
+        match n {
+            0..=7 => cpu.set_gr(n as u8, value as u16),
+            8 => cpu.pc = value as u16,
+            9 => cpu.sp = value as u16,
+            10 => cpu.psr = value as u16,
+            11 => cpu.tr = value,
+            12 => cpu.tlr = value as u16,
+            13 => cpu.thr = value as u16,
+            14 => cpu.ppc = value as u16,
+            _ => cpu.ppsr = value as u16,
+        }
+    }
+
+    fn reg_size(n: usize) -> usize {
+        if n == REG_TR {
+            4
+        } else {
+            2
+        }
+    }
+
+    // little endian, as in memory
+    fn encode_reg(&self, n: usize) -> String {
+        hex::encode(&self.reg(n).to_le_bytes()[..Self::reg_size(n)])
+    }
+
+    fn decode_reg(n: usize, bytes: &[u8]) -> u32 {
+        let mut value = [0u8; 4];
+        value[..Self::reg_size(n)].copy_from_slice(&bytes[..Self::reg_size(n)]);
+        u32::from_le_bytes(value)
+    }
+
+    fn write_regs(&mut self, args: &str) -> String {
+        let Ok(bytes) = hex::decode(args) else {
+            return "E01".to_string();
+        };
+        let total: usize = (0..REG_COUNT).map(Self::reg_size).sum();
+        if bytes.len() < total {
+            return "E01".to_string();
+        }
+        let mut offset = 0;
+        for n in 0..REG_COUNT {
+            let value = Self::decode_reg(n, &bytes[offset..]);
+            self.set_reg(n, value);
+            offset += Self::reg_size(n);
+        }
+        "OK".to_string()
+    }
+
+    fn write_reg(&mut self, args: &str) -> String {
+        let Some((n, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        match (usize::from_str_radix(n, 16), hex::decode(value)) {
+            (Ok(n), Ok(bytes)) if n < REG_COUNT && bytes.len() >= Self::reg_size(n) => {
+                let value = Self::decode_reg(n, &bytes);
+                self.set_reg(n, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_pair(args) else {
+            return "E01".to_string();
+        };
+        let mut bytes = vec![];
+        for i in 0..len {
+            match self.zktc.memory().read_byte(addr.wrapping_add(i)) {
+                Ok(byte) => bytes.push(byte),
+                Err(_) if i > 0 => break,
+                Err(_) => return "E01".to_string(),
+            }
+        }
+        hex::encode(bytes)
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Ok(bytes)) = (parse_pair(range), hex::decode(data)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != len as usize {
+            return "E01".to_string();
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            let addr = addr.wrapping_add(i as u16);
+            if self.zktc.memory_mut().write_byte(addr, byte).is_err() {
+                return "E01".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    // $<data>#<checksum>
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // skip acks and interrupts that arrive while stopped
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'$') => break,
+                    Some(_) => continue,
+                }
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0u8; 2];
+            for c in checksum.iter_mut() {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(byte) => *c = byte,
+                }
+            }
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok());
+            let valid = expected == Some(Self::checksum(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid || self.no_ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, Self::checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    fn checksum(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    // Checks for a ctrl-c (0x03) without blocking. Other bytes are kept for read_packet.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 64];
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => {
+                self.closed = true;
+                Ok(true)
+            }
+            Ok(n) => {
+                let interrupted = buf[..n].contains(&0x03);
+                self.pending.extend(buf[..n].iter().filter(|b| **b != 0x03));
+                Ok(interrupted)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn parse_pair(args: &str) -> Option<(u16, u16)> {
+    let (a, b) = args.split_once(',')?;
+    Some((
+        u16::from_str_radix(a, 16).ok()?,
+        u16::from_str_radix(b, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::loader::Image;
+    use super::super::machine::{Machine, RegionKind};
+    use super::super::{asm, history, memory};
+    use super::*;
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send_only(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, GdbStub::checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0u8; 1];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn send(&mut self, data: &str) -> String {
+            self.send_only(data);
+
+            let mut reply = vec![];
+            let mut byte = [0u8; 1];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            assert_eq!(reply[0], b'$');
+            String::from_utf8(reply[1..].to_vec()).unwrap()
+        }
+    }
+
+    fn start(path: &str) -> (Client, thread::JoinHandle<Zktc>) {
+        let src = std::fs::read_to_string(path).unwrap();
+        let rom = asm::assemble(&src, memory::ROM_LOW_ADDRESS).unwrap();
+        let mut machine = Machine::load("test/machine.toml").unwrap();
+        machine
+            .set_image(RegionKind::Rom, &Image::relative(rom))
+            .unwrap();
+        let mut zktc = Zktc::from_machine(&machine).unwrap();
+        zktc.set_history_budget(history::DEFAULT_BUDGET);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            serve(&mut zktc, listener).unwrap();
+            zktc
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_nodelay(true).unwrap();
+        (Client { stream }, handle)
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let (mut client, handle) = start("test/asm/add_test.asm");
+        assert!(client.send("qSupported").contains("qXfer:features:read+"));
+        let xml = client.send("qXfer:features:read:target.xml:0,4000");
+        assert!(xml.starts_with('l') && xml.contains("name=\"ppsr\""));
+        assert_eq!(client.send("?"), "S05");
+
+        let regs = client.send("g");
+        assert_eq!(regs.len(), (15 * 2 + 4) * 2);
+        assert_eq!(&regs[32..36], "00b0");
+        assert_eq!(client.send("P1=3412"), "OK");
+        assert_eq!(client.send("p1"), "3412");
+        assert_eq!(client.send("pb"), "00000000");
+
+        assert_eq!(client.send("mb000,2"), "f1fe");
+        assert_eq!(client.send("M10,3:aabbcc"), "OK");
+        assert_eq!(client.send("m10,4"), "aabbcc00");
+        assert_eq!(client.send("m9000,2"), "E01");
+
+        client.send_only("k");
+        let zktc = handle.join().unwrap();
+        assert_eq!(zktc.read_gr(1), 0x1234);
+        assert_eq!(zktc.read_memory(0x10, false), Ok(0xbbaa));
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let (mut client, handle) = start("test/asm/add_test.asm");
+        assert_eq!(client.send("Z0,b004,2"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p8"), "04b0");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p8"), "06b0");
+        assert_eq!(client.send("z0,b004,2"), "OK");
+
+        assert_eq!(client.send("Z2,fffc,4"), "OK");
+        assert_eq!(client.send("c"), "T05watch:fffe;");
+        assert_eq!(client.send("mfffe,1"), "01");
+        assert_eq!(client.send("z2,fffc,4"), "OK");
+        assert_eq!(client.send("c"), "W00");
+        assert_eq!(client.send("D"), "OK");
+
+        let zktc = handle.join().unwrap();
+        assert!(zktc.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let (mut client, handle) = start("test/asm/add_test.asm");
+        assert!(client.send("qSupported").contains("ReverseContinue+"));
+        assert_eq!(client.send("bs"), "T05replaylog:begin;");
+        assert_eq!(client.send("Z0,b004,2"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p8"), "08b0");
+        assert_eq!(client.send("bc"), "S05");
+        assert_eq!(client.send("p8"), "04b0");
+        assert_eq!(client.send("bs"), "S05");
+        assert_eq!(client.send("p8"), "02b0");
+        assert_eq!(client.send("bc"), "T05replaylog:begin;");
+        assert_eq!(client.send("p8"), "00b0");
+        client.send_only("k");
+
+        let zktc = handle.join().unwrap();
+        assert_eq!(zktc.steps(), 0);
+    }
+}
@@ -0,0 +1,178 @@
+use super::bus::{self, DeviceStates};
+use super::cpu::Cpu;
+use super::memory::MemoryAccess;
+use super::timer::TimerState;
+use std::collections::VecDeque;
+
+pub const DEFAULT_BUDGET: usize = 64 << 20;
+
+// What is needed to undo one step. old is the memory value before a write, zero extended for
+// byte writes, and is not used for reads. devices holds the state of the devices other than
+// plain memory the step accessed, such as pending interrupts and received bytes, see
+// Bus::record_devices. Sent bytes stay sent.
+// retired is false when the step entered the trap handler because the instruction could not
+// be fetched or decoded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndoEntry {
+    pub cpu: Cpu,
+    pub timer: TimerState,
+    pub access: Option<MemoryAccess>,
+    pub old: u16,
+    pub devices: DeviceStates,
+    pub retired: bool,
+}
+
+pub const ENTRY_SIZE: usize = std::mem::size_of::<UndoEntry>();
+
+impl UndoEntry {
+    // bytes used by the entry, with the saved device states
+    pub fn size(&self) -> usize {
+        ENTRY_SIZE + bus::states_size(&self.devices)
+    }
+}
+
+// Undo log of the latest steps. The oldest entries are dropped to stay within the budget in
+// bytes, a budget of 0 turns recording off.
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<UndoEntry>,
+    budget: usize,
+    usage: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl History {
+    pub fn new(budget: usize) -> Self {
+        History {
+            entries: VecDeque::new(),
+            budget,
+            usage: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget >= ENTRY_SIZE
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.trim();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // bytes used by the entries
+    pub fn usage(&self) -> usize {
+        self.usage
+    }
+
+    pub fn push(&mut self, entry: UndoEntry) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.usage += entry.size();
+        self.entries.push_back(entry);
+        self.trim();
+    }
+
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        let entry = self.entries.pop_back()?;
+        self.usage -= entry.size();
+        Some(entry)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.usage = 0;
+    }
+
+    fn trim(&mut self) {
+        while self.usage > self.budget {
+            let Some(entry) = self.entries.pop_front() else {
+                break;
+            };
+            self.usage -= entry.size();
+        }
+    }
+}
+
+// "4096", "64k" or "16M", in bytes
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (number, shift) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 10),
+        (i, 'm' | 'M') => (&s[..i], 20),
+        _ => (s, 0),
+    };
+    number.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(pc: u16) -> UndoEntry {
+        let mut cpu = Cpu::new();
+        cpu.pc = pc;
+        UndoEntry {
+            cpu,
+            timer: TimerState::default(),
+            access: None,
+            old: 0,
+            devices: vec![],
+            retired: true,
+        }
+    }
+
+    #[test]
+    fn budget_drops_oldest_entries() {
+        let mut history = History::new(ENTRY_SIZE * 2);
+        history.push(entry(0xb000));
+        history.push(entry(0xb002));
+        history.push(entry(0xb004));
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.usage(), ENTRY_SIZE * 2);
+        assert_eq!(history.pop().unwrap().cpu.pc, 0xb004);
+        history.set_budget(0);
+        assert!(history.is_empty());
+        history.push(entry(0xb006));
+        assert!(history.pop().is_none());
+    }
+
+    #[test]
+    fn budget_counts_device_states() {
+        let mut large = entry(0xb000);
+        large.devices = vec![(3, vec![0; ENTRY_SIZE])];
+        let mut history = History::new(ENTRY_SIZE * 3);
+        history.push(entry(0xb000));
+        history.push(large.clone());
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.usage(), large.size());
+        assert!(large.size() > ENTRY_SIZE * 2);
+        history.pop();
+        assert_eq!(history.usage(), 0);
+    }
+
+    #[test]
+    fn parse_size_test() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("16M"), Some(16 << 20));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size(""), None);
+    }
+}
@@ -0,0 +1,425 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // R instruction
+    Mov { rd: u8, rs: u8 },
+    Add { rd: u8, rs: u8 },
+    Sub { rd: u8, rs: u8 },
+    And { rd: u8, rs: u8 },
+    Or { rd: u8, rs: u8 },
+    Xor { rd: u8, rs: u8 },
+    Sll { rd: u8, rs: u8 },
+    Srl { rd: u8, rs: u8 },
+    Sra { rd: u8, rs: u8 },
+
+    // I5 instruction
+    Addi { rd: u8, rs: u8, imm: u16 },
+    Subi { rd: u8, rs: u8, imm: u16 },
+    Beq { rd: u8, rs: u8, imm: i16 },
+    Bnq { rd: u8, rs: u8, imm: i16 },
+    Blt { rd: u8, rs: u8, imm: i16 },
+    Bge { rd: u8, rs: u8, imm: i16 },
+    Bltu { rd: u8, rs: u8, imm: i16 },
+    Bgeu { rd: u8, rs: u8, imm: i16 },
+    Jalr { rd: u8, rs: u8, imm: i16 },
+    Lh { rd: u8, rs: u8, imm: i16 },
+    Lhu { rd: u8, rs: u8, imm: i16 },
+    Lw { rd: u8, rs: u8, imm: i16 },
+    Sh { rd: u8, rs: u8, imm: i16 },
+    Sw { rd: u8, rs: u8, imm: i16 },
+
+    // I8 instruction
+    Jal { rd: u8, imm: i16 },
+    Lil { rd: u8, imm: u16 },
+    Lih { rd: u8, imm: u16 },
+
+    // C1 instruction
+    Push { rd: u8 },
+    Pop { rd: u8 },
+    Rpc { rd: u8 },
+    Rsp { rd: u8 },
+    Rpsr { rd: u8 },
+    Rtlr { rd: u8 },
+    Rthr { rd: u8 },
+    Rppc { rd: u8 },
+    Rppsr { rd: u8 },
+    Wsp { rd: u8 },
+    Wpsr { rd: u8 },
+    Wtlr { rd: u8 },
+    Wthr { rd: u8 },
+    Wppc { rd: u8 },
+    Wppsr { rd: u8 },
+
+    // C2 instruction
+    Rfi,
+    Rtr,
+    Wtr,
+
+    Trap,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum DecodeError {
+    #[error("unknown instruction 0x{0:04x}")]
+    UnknownInstruction(u16),
+}
+
+pub fn decode(word: u16) -> Result<Instruction, DecodeError> {
+    use Instruction::*;
+
+    let opcode = word & 0x001F;
+    let rd = ((word & 0x00E0) >> 5) as u8;
+    let rs = ((word & 0x0700) >> 8) as u8;
+    let func = (word & 0xF800) >> 11;
+    let imm_i5 = (word & 0xF800) >> 11;
+    let imm_i5_sext = ((word & 0xF800) as i16) >> 11;
+    let imm_i8 = (word & 0xFF00) >> 8;
+    let imm_i8_sext = ((word & 0xFF00) as i16) >> 8;
+
+    let inst = match opcode {
+        0b00000 => match func {
+            0b0001 => Mov { rd, rs },
+            0b0010 => Add { rd, rs },
+            0b0011 => Sub { rd, rs },
+            0b0100 => And { rd, rs },
+            0b0101 => Or { rd, rs },
+            0b0110 => Xor { rd, rs },
+            0b0111 => Sll { rd, rs },
+            0b1000 => Srl { rd, rs },
+            0b1001 => Sra { rd, rs },
+            _ => Err(DecodeError::UnknownInstruction(word))?,
+        },
+        0b00001 => Addi {
+            rd,
+            rs,
+            imm: imm_i5,
+        },
+        0b00010 => Subi {
+            rd,
+            rs,
+            imm: imm_i5,
+        },
+        0b00011 => Beq {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b00100 => Bnq {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b00101 => Blt {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b00110 => Bge {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b00111 => Bltu {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01000 => Bgeu {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01001 => Jalr {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01010 => Lh {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01011 => Lhu {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01100 => Lw {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01101 => Sh {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b01110 => Sw {
+            rd,
+            rs,
+            imm: imm_i5_sext,
+        },
+        0b10000 => Jal {
+            rd,
+            imm: imm_i8_sext,
+        },
+        0b10001 => Lil { rd, imm: imm_i8 },
+        0b10010 => Lih { rd, imm: imm_i8 },
+        0b11110 => match func {
+            0b00001 => Push { rd },
+            0b00010 => Pop { rd },
+            0b00011 => Rpc { rd },
+            0b00100 => Rsp { rd },
+            0b00101 => Rpsr { rd },
+            0b00110 => Rtlr { rd },
+            0b00111 => Rthr { rd },
+            0b01000 => Rppc { rd },
+            0b01001 => Rppsr { rd },
+            0b01010 => Wsp { rd },
+            0b01011 => Wpsr { rd },
+            0b01100 => Wtlr { rd },
+            0b01101 => Wthr { rd },
+            0b01110 => Wppc { rd },
+            0b01111 => Wppsr { rd },
+            _ => Err(DecodeError::UnknownInstruction(word))?,
+        },
+        0b11111 => match func {
+            0b00001 => Rfi,
+            0b00010 => Rtr,
+            0b00011 => Wtr,
+            _ => {
+                if word == 0xFFFF {
+                    Trap
+                } else {
+                    Err(DecodeError::UnknownInstruction(word))?
+                }
+            }
+        },
+        _ => Err(DecodeError::UnknownInstruction(word))?,
+    };
+    Ok(inst)
+}
+
+impl Instruction {
+    pub fn encode(&self) -> u16 {
+        use Instruction::*;
+
+        let r = |opcode: u16, rd: u8, rs: u8| opcode | (rd as u16) << 5 | (rs as u16) << 8;
+        let c1 = |func: u16, rd: u8| 0b11110 | (rd as u16) << 5 | func << 11;
+        let c2 = |func: u16| 0b11111 | func << 11;
+
+        match *self {
+            Mov { rd, rs } => r(0, rd, rs) | 0b0001 << 11,
+            Add { rd, rs } => r(0, rd, rs) | 0b0010 << 11,
+            Sub { rd, rs } => r(0, rd, rs) | 0b0011 << 11,
+            And { rd, rs } => r(0, rd, rs) | 0b0100 << 11,
+            Or { rd, rs } => r(0, rd, rs) | 0b0101 << 11,
+            Xor { rd, rs } => r(0, rd, rs) | 0b0110 << 11,
+            Sll { rd, rs } => r(0, rd, rs) | 0b0111 << 11,
+            Srl { rd, rs } => r(0, rd, rs) | 0b1000 << 11,
+            Sra { rd, rs } => r(0, rd, rs) | 0b1001 << 11,
+            Addi { rd, rs, imm } => r(0b00001, rd, rs) | imm << 11,
+            Subi { rd, rs, imm } => r(0b00010, rd, rs) | imm << 11,
+            Beq { rd, rs, imm } => r(0b00011, rd, rs) | (imm as u16) << 11,
+            Bnq { rd, rs, imm } => r(0b00100, rd, rs) | (imm as u16) << 11,
+            Blt { rd, rs, imm } => r(0b00101, rd, rs) | (imm as u16) << 11,
+            Bge { rd, rs, imm } => r(0b00110, rd, rs) | (imm as u16) << 11,
+            Bltu { rd, rs, imm } => r(0b00111, rd, rs) | (imm as u16) << 11,
+            Bgeu { rd, rs, imm } => r(0b01000, rd, rs) | (imm as u16) << 11,
+            Jalr { rd, rs, imm } => r(0b01001, rd, rs) | (imm as u16) << 11,
+            Lh { rd, rs, imm } => r(0b01010, rd, rs) | (imm as u16) << 11,
+            Lhu { rd, rs, imm } => r(0b01011, rd, rs) | (imm as u16) << 11,
+            Lw { rd, rs, imm } => r(0b01100, rd, rs) | (imm as u16) << 11,
+            Sh { rd, rs, imm } => r(0b01101, rd, rs) | (imm as u16) << 11,
+            Sw { rd, rs, imm } => r(0b01110, rd, rs) | (imm as u16) << 11,
+            Jal { rd, imm } => r(0b10000, rd, 0) | (imm as u16) << 8,
+            Lil { rd, imm } => r(0b10001, rd, 0) | imm << 8,
+            Lih { rd, imm } => r(0b10010, rd, 0) | imm << 8,
+            Push { rd } => c1(0b00001, rd),
+            Pop { rd } => c1(0b00010, rd),
+            Rpc { rd } => c1(0b00011, rd),
+            Rsp { rd } => c1(0b00100, rd),
+            Rpsr { rd } => c1(0b00101, rd),
+            Rtlr { rd } => c1(0b00110, rd),
+            Rthr { rd } => c1(0b00111, rd),
+            Rppc { rd } => c1(0b01000, rd),
+            Rppsr { rd } => c1(0b01001, rd),
+            Wsp { rd } => c1(0b01010, rd),
+            Wpsr { rd } => c1(0b01011, rd),
+            Wtlr { rd } => c1(0b01100, rd),
+            Wthr { rd } => c1(0b01101, rd),
+            Wppc { rd } => c1(0b01110, rd),
+            Wppsr { rd } => c1(0b01111, rd),
+            Rfi => c2(0b00001),
+            Rtr => c2(0b00010),
+            Wtr => c2(0b00011),
+            Trap => 0xFFFF,
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        use Instruction::*;
+
+        match self {
+            Mov { .. } => "mov",
+            Add { .. } => "add",
+            Sub { .. } => "sub",
+            And { .. } => "and",
+            Or { .. } => "or",
+            Xor { .. } => "xor",
+            Sll { .. } => "sll",
+            Srl { .. } => "srl",
+            Sra { .. } => "sra",
+            Addi { .. } => "addi",
+            Subi { .. } => "subi",
+            Beq { .. } => "beq",
+            Bnq { .. } => "bnq",
+            Blt { .. } => "blt",
+            Bge { .. } => "bge",
+            Bltu { .. } => "bltu",
+            Bgeu { .. } => "bgeu",
+            Jalr { .. } => "jalr",
+            Lh { .. } => "lh",
+            Lhu { .. } => "lhu",
+            Lw { .. } => "lw",
+            Sh { .. } => "sh",
+            Sw { .. } => "sw",
+            Jal { .. } => "jal",
+            Lil { .. } => "lil",
+            Lih { .. } => "lih",
+            Push { .. } => "push",
+            Pop { .. } => "pop",
+            Rpc { .. } => "rpc",
+            Rsp { .. } => "rsp",
+            Rpsr { .. } => "rpsr",
+            Rtlr { .. } => "rtlr",
+            Rthr { .. } => "rthr",
+            Rppc { .. } => "rppc",
+            Rppsr { .. } => "rppsr",
+            Wsp { .. } => "wsp",
+            Wpsr { .. } => "wpsr",
+            Wtlr { .. } => "wtlr",
+            Wthr { .. } => "wthr",
+            Wppc { .. } => "wppc",
+            Wppsr { .. } => "wppsr",
+            Rfi => "rfi",
+            Rtr => "rtr",
+            Wtr => "wtr",
+            Trap => "trap",
+        }
+    }
+}
+
+// Same operand layout as the per-instruction trace: "addi x1 x2 3".
+// The alternate form "{:#}" separates operands with commas as zktc-asm expects: "addi x1, x2, 3".
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+
+        let mnemonic = self.mnemonic();
+        let sep = if f.alternate() { ", " } else { " " };
+        match *self {
+            Mov { rd, rs }
+            | Add { rd, rs }
+            | Sub { rd, rs }
+            | And { rd, rs }
+            | Or { rd, rs }
+            | Xor { rd, rs }
+            | Sll { rd, rs }
+            | Srl { rd, rs }
+            | Sra { rd, rs } => write!(f, "{} x{}{}x{}", mnemonic, rd, sep, rs),
+            Addi { rd, rs, imm } | Subi { rd, rs, imm } => {
+                write!(f, "{} x{}{}x{}{}{}", mnemonic, rd, sep, rs, sep, imm)
+            }
+            Beq { rd, rs, imm }
+            | Bnq { rd, rs, imm }
+            | Blt { rd, rs, imm }
+            | Bge { rd, rs, imm }
+            | Bltu { rd, rs, imm }
+            | Bgeu { rd, rs, imm }
+            | Jalr { rd, rs, imm }
+            | Lh { rd, rs, imm }
+            | Lhu { rd, rs, imm }
+            | Lw { rd, rs, imm }
+            | Sh { rd, rs, imm }
+            | Sw { rd, rs, imm } => write!(f, "{} x{}{}x{}{}{}", mnemonic, rd, sep, rs, sep, imm),
+            Jal { rd, imm } => write!(f, "{} x{}{}{}", mnemonic, rd, sep, imm),
+            Lil { rd, imm } | Lih { rd, imm } => write!(f, "{} x{}{}{}", mnemonic, rd, sep, imm),
+            Push { rd }
+            | Pop { rd }
+            | Rpc { rd }
+            | Rsp { rd }
+            | Rpsr { rd }
+            | Rtlr { rd }
+            | Rthr { rd }
+            | Rppc { rd }
+            | Rppsr { rd }
+            | Wsp { rd }
+            | Wpsr { rd }
+            | Wtlr { rd }
+            | Wthr { rd }
+            | Wppc { rd }
+            | Wppsr { rd } => write!(f, "{} x{}", mnemonic, rd),
+            Rfi | Rtr | Wtr | Trap => write!(f, "{}", mnemonic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_r_test() {
+        // add x1 x2
+        assert_eq!(decode(0x1220), Ok(Instruction::Add { rd: 1, rs: 2 }));
+    }
+
+    #[test]
+    fn decode_i5_sext_test() {
+        // beq x2 x3 -4
+        assert_eq!(
+            decode(0xe343),
+            Ok(Instruction::Beq {
+                rd: 2,
+                rs: 3,
+                imm: -4
+            })
+        );
+    }
+
+    #[test]
+    fn decode_i8_test() {
+        // lil x7 0xfe
+        assert_eq!(decode(0xfef1), Ok(Instruction::Lil { rd: 7, imm: 0xfe }));
+    }
+
+    #[test]
+    fn decode_c_test() {
+        assert_eq!(decode(0x083e), Ok(Instruction::Push { rd: 1 }));
+        assert_eq!(decode(0x081f), Ok(Instruction::Rfi));
+        assert_eq!(decode(0xffff), Ok(Instruction::Trap));
+    }
+
+    #[test]
+    fn decode_unknown_test() {
+        assert_eq!(decode(0x0000), Err(DecodeError::UnknownInstruction(0x0000)));
+        assert_eq!(decode(0x0013), Err(DecodeError::UnknownInstruction(0x0013)));
+        assert_eq!(decode(0xf81f), Err(DecodeError::UnknownInstruction(0xf81f)));
+    }
+
+    #[test]
+    fn encode_test() {
+        for word in [0x1220, 0xe343, 0xfef1, 0x083e, 0x081f, 0xffff] {
+            assert_eq!(decode(word).unwrap().encode(), word);
+        }
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(decode(0xe343).unwrap().to_string(), "beq x2 x3 -4");
+        assert_eq!(decode(0x083e).unwrap().to_string(), "push x1");
+        assert_eq!(format!("{:#}", decode(0xe343).unwrap()), "beq x2, x3, -4");
+    }
+}
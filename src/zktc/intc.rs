@@ -0,0 +1,134 @@
+use super::bus::Device;
+
+pub const DEFAULT_BASE: u16 = 0xA000;
+
+// Register offsets. A bit of PENDING is set when its line is raised and cleared by writing 1
+// to it, a line interrupts the cpu only while its bit of MASK is set.
+pub const PENDING: u16 = 0;
+pub const MASK: u16 = 2;
+pub const SIZE: u16 = 4;
+
+pub const LINES: u8 = 16;
+pub const TIMER_LINE: u8 = 0;
+pub const UART_LINE: u8 = 1;
+
+// All lines are enabled after reset.
+const RESET_MASK: u16 = 0xffff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InterruptController {
+    pending: u16,
+    mask: u16,
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController {
+            pending: 0,
+            mask: RESET_MASK,
+        }
+    }
+
+    fn write_register(&mut self, offset: u16, data: u16) {
+        match offset {
+            PENDING => self.pending &= !data,
+            MASK => self.mask = data,
+            _ => {}
+        }
+    }
+
+    fn register(&self, offset: u16) -> u16 {
+        match offset {
+            PENDING => self.pending,
+            MASK => self.mask,
+            _ => 0,
+        }
+    }
+}
+
+impl Device for InterruptController {
+    // A byte write clears or sets only its half of the register.
+    fn write8(&mut self, offset: u16, data: u8) {
+        let register = offset & !1;
+        let shift = (offset & 1) * 8;
+        let data = (data as u16) << shift;
+        match register {
+            PENDING => self.write_register(PENDING, data),
+            _ => {
+                let keep = self.register(register) & !(0xff << shift);
+                self.write_register(register, keep | data);
+            }
+        }
+    }
+
+    fn write16(&mut self, offset: u16, data: u16) {
+        self.write_register(offset, data);
+    }
+
+    fn peek8(&self, offset: u16) -> u8 {
+        (self.register(offset & !1) >> ((offset & 1) * 8)) as u8
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn irq(&self) -> bool {
+        self.pending & self.mask != 0
+    }
+
+    fn raise(&mut self, lines: u16) {
+        self.pending |= lines;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.pending.to_le_bytes().to_vec();
+        state.extend(self.mask.to_le_bytes());
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let [pending_low, pending_high, mask_low, mask_high] = state else {
+            return Err(format!("{} bytes for 4 bytes of registers", state.len()));
+        };
+        self.pending = u16::from_le_bytes([*pending_low, *pending_high]);
+        self.mask = u16::from_le_bytes([*mask_low, *mask_high]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pending_and_mask() {
+        let mut intc = InterruptController::new();
+        assert!(!intc.irq());
+        intc.raise(1 << TIMER_LINE | 1 << 5);
+        assert_eq!(intc.read16(PENDING), 0x0021);
+        assert!(intc.irq());
+
+        intc.write16(MASK, 1 << 5);
+        intc.write16(PENDING, 1 << 5);
+        assert_eq!(intc.peek16(PENDING), 0x0001);
+        assert!(!intc.irq());
+        intc.write8(MASK, 0x01);
+        assert_eq!(intc.peek16(MASK), 0x0001);
+        assert!(intc.irq());
+        intc.write8(PENDING + 1, 0xff);
+        assert_eq!(intc.peek16(PENDING), 0x0001);
+
+        let state = intc.save_state();
+        intc.reset();
+        assert_eq!((intc.peek16(PENDING), intc.peek16(MASK)), (0, 0xffff));
+        intc.load_state(&state).unwrap();
+        assert_eq!((intc.peek16(PENDING), intc.peek16(MASK)), (1, 1));
+    }
+}
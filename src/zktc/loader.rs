@@ -0,0 +1,469 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(thiserror::Error, Debug)]
+pub enum LoaderError {
+    #[error("could not read image file '{0}'")]
+    Io(String, #[source] std::io::Error),
+
+    // line and column start at 1
+    #[error("line {0}, column {1} : {2}")]
+    Syntax(usize, usize, String),
+
+    #[error("image does not fit in 0x{0:04x}-0x{1:04x}")]
+    OutOfRegion(u16, u16),
+}
+
+// Image file formats. Intel HEX and S-records carry the address of their data, mem and binary
+// images are placed at a base address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Mem,
+    IntelHex,
+    SRecord,
+    Binary,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Mem => write!(f, "mem"),
+            Format::IntelHex => write!(f, "ihex"),
+            Format::SRecord => write!(f, "srec"),
+            Format::Binary => write!(f, "bin"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mem" => Ok(Format::Mem),
+            "ihex" => Ok(Format::IntelHex),
+            "srec" => Ok(Format::SRecord),
+            "bin" => Ok(Format::Binary),
+            _ => Err(format!("unknown image format '{}'", s)),
+        }
+    }
+}
+
+// Bytes starting at address, which is an offset from the base of a relative image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Image {
+    pub absolute: bool,
+    pub segments: Vec<Segment>,
+}
+
+impl Image {
+    // An image of bytes placed at its base address.
+    pub fn relative(bytes: Vec<u8>) -> Self {
+        Image {
+            absolute: false,
+            segments: vec![Segment { address: 0, bytes }],
+        }
+    }
+
+    // The address of the first byte of each segment when the image is placed at base.
+    pub fn placed(&self, base: u16) -> impl Iterator<Item = (u16, &[u8])> {
+        let offset = if self.absolute { 0 } else { base };
+        self.segments
+            .iter()
+            .map(move |s| (s.address.wrapping_add(offset), s.bytes.as_slice()))
+    }
+
+    // The contents of a region of size bytes at base, from its start to the last byte of the
+    // image. A relative image starts at base.
+    pub fn region_bytes(&self, base: u16, size: u16) -> Result<Vec<u8>, LoaderError> {
+        let end = base as u32 + size as u32;
+        let mut bytes = vec![];
+        for (address, data) in self.placed(base) {
+            if data.is_empty() {
+                continue;
+            }
+            if address < base || address as u32 + data.len() as u32 > end {
+                Err(LoaderError::OutOfRegion(base, (end - 1) as u16))?
+            }
+            let offset = (address - base) as usize;
+            if bytes.len() < offset + data.len() {
+                bytes.resize(offset + data.len(), 0);
+            }
+            bytes[offset..offset + data.len()].copy_from_slice(data);
+        }
+        Ok(bytes)
+    }
+
+    // Appends bytes at address, merged with the last segment when they follow it.
+    fn push(&mut self, address: u32, data: &[u8]) -> Result<(), String> {
+        if address as u64 + data.len() as u64 > 0x10000 {
+            Err(format!(
+                "data at 0x{:x} does not fit in the address space",
+                address
+            ))?
+        }
+        match self.segments.last_mut() {
+            Some(last) if last.address as u32 + last.bytes.len() as u32 == address => {
+                last.bytes.extend_from_slice(data)
+            }
+            _ => self.segments.push(Segment {
+                address: address as u16,
+                bytes: data.to_vec(),
+            }),
+        }
+        Ok(())
+    }
+}
+
+// A mem file is a list of hex strings separated by whitespace, as emitted by zktc-asm.
+pub fn load_mem_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, LoaderError> {
+    let path = path.as_ref();
+    let f = std::fs::read_to_string(path)
+        .map_err(|e| LoaderError::Io(path.display().to_string(), e))?;
+    parse_mem(&f)
+}
+
+// The format is detected from the content unless it is given.
+pub fn load_image<P: AsRef<Path>>(path: P, format: Option<Format>) -> Result<Image, LoaderError> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path).map_err(|e| LoaderError::Io(path.display().to_string(), e))?;
+    parse_image(&bytes, format.unwrap_or_else(|| detect(&bytes)))
+}
+
+// Text starting with ':' is Intel HEX, with 'S' and a digit an S-record file, made of hex
+// digits a mem file, and anything else binary.
+pub fn detect(bytes: &[u8]) -> Format {
+    let Ok(text) = std::str::from_utf8(bytes) else {
+        return Format::Binary;
+    };
+    let text = text.trim_start();
+    let mut chars = text.chars();
+    match (chars.next(), chars.next()) {
+        (None, _) => Format::Mem,
+        (Some(':'), _) => Format::IntelHex,
+        (Some('S'), Some(c)) if c.is_ascii_digit() => Format::SRecord,
+        _ if text
+            .chars()
+            .all(|c| c.is_ascii_hexdigit() || c.is_whitespace()) =>
+        {
+            Format::Mem
+        }
+        _ => Format::Binary,
+    }
+}
+
+pub fn parse_image(bytes: &[u8], format: Format) -> Result<Image, LoaderError> {
+    let text = || {
+        std::str::from_utf8(bytes).map_err(|e| {
+            let (line, column) = position(bytes, e.valid_up_to());
+            LoaderError::Syntax(line, column, "not a text file".to_string())
+        })
+    };
+    match format {
+        Format::Mem => Ok(Image::relative(parse_mem(text()?)?)),
+        Format::IntelHex => parse_intel_hex(text()?),
+        Format::SRecord => parse_srecord(text()?),
+        Format::Binary => Ok(Image::relative(bytes.to_vec())),
+    }
+}
+
+// line and column of a byte offset
+fn position(bytes: &[u8], offset: usize) -> (usize, usize) {
+    let before = &bytes[..offset];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    (line, offset - start + 1)
+}
+
+pub fn parse_mem(text: &str) -> Result<Vec<u8>, LoaderError> {
+    let mut bytes: Vec<u8> = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let mut column = 0;
+        for word in line.split_whitespace() {
+            column += line[column..].find(word).unwrap();
+            let mut hex = hex::decode(word).map_err(|_| {
+                LoaderError::Syntax(
+                    number + 1,
+                    column + 1,
+                    format!("could not decode '{}' to hex", word),
+                )
+            })?;
+            bytes.append(&mut hex);
+            column += word.len();
+        }
+    }
+    Ok(bytes)
+}
+
+// The hex bytes of a record after its start code, the column of each byte is 2 * i + 2.
+fn record_bytes(line: &str, number: usize) -> Result<Vec<u8>, LoaderError> {
+    let digits = &line[1..];
+    if let Some(i) = digits.find(|c: char| !c.is_ascii_hexdigit()) {
+        Err(LoaderError::Syntax(
+            number,
+            i + 2,
+            "expected a hex digit".to_string(),
+        ))?
+    }
+    if digits.len() & 1 != 0 {
+        Err(LoaderError::Syntax(
+            number,
+            line.len(),
+            "odd number of hex digits".to_string(),
+        ))?
+    }
+    Ok(hex::decode(digits).unwrap())
+}
+
+// Records are :LLAAAATT<data>CC. Extended segment (02) and linear (04) addresses must keep the
+// data below 0x10000.
+pub fn parse_intel_hex(text: &str) -> Result<Image, LoaderError> {
+    let mut image = Image {
+        absolute: true,
+        segments: vec![],
+    };
+    let mut upper: u32 = 0;
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let error =
+            |column, message: &str| LoaderError::Syntax(number, column, message.to_string());
+        if !line.starts_with(':') {
+            Err(error(1, "expected ':'"))?
+        }
+        let record = record_bytes(line, number)?;
+        if record.len() < 5 {
+            Err(error(line.len(), "record is too short"))?
+        }
+        let count = record[0] as usize;
+        if record.len() != count + 5 {
+            Err(error(2, "byte count does not match the record"))?
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            Err(error(line.len() - 1, "checksum mismatch"))?
+        }
+        let address = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..4 + count];
+        match record[3] {
+            0x00 => image
+                .push(upper + address, data)
+                .map_err(|e| error(4, &e))?,
+            0x01 => break,
+            0x02 if count == 2 => upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if count == 2 => upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // start addresses
+            0x03 | 0x05 => {}
+            0x02 | 0x04 => Err(error(2, "extended address records have 2 bytes"))?,
+            _ => Err(error(8, "unknown record type"))?,
+        }
+    }
+    Ok(image)
+}
+
+// Records are S<type><count><address><data><checksum>, with 2 (S1), 3 (S2) or 4 (S3) address
+// bytes. Headers, counts and start addresses are skipped.
+pub fn parse_srecord(text: &str) -> Result<Image, LoaderError> {
+    let mut image = Image {
+        absolute: true,
+        segments: vec![],
+    };
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let error =
+            |column, message: &str| LoaderError::Syntax(number, column, message.to_string());
+        let mut chars = line.chars();
+        if chars.next() != Some('S') {
+            Err(error(1, "expected 'S'"))?
+        }
+        let address_len = match chars.next() {
+            Some('0' | '1' | '5' | '9') => 2,
+            Some('2' | '6' | '8') => 3,
+            Some('3' | '7') => 4,
+            _ => Err(error(2, "unknown record type"))?,
+        };
+        // the type digit is not part of the hex bytes
+        let record = record_bytes(&line[1..], number).map_err(|e| match e {
+            LoaderError::Syntax(line, column, message) => {
+                LoaderError::Syntax(line, column + 1, message)
+            }
+            e => e,
+        })?;
+        if record.len() < 1 + address_len + 1 {
+            Err(error(line.len(), "record is too short"))?
+        }
+        if record[0] as usize != record.len() - 1 {
+            Err(error(3, "byte count does not match the record"))?
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            Err(error(line.len() - 1, "checksum mismatch"))?
+        }
+        let address = record[1..1 + address_len]
+            .iter()
+            .fold(0u32, |address, b| address << 8 | *b as u32);
+        let data = &record[1 + address_len..record.len() - 1];
+        if let Some('1' | '2' | '3') = line.chars().nth(1) {
+            image.push(address, data).map_err(|e| error(5, &e))?;
+        }
+    }
+    Ok(image)
+}
+
+// One little endian word per line.
+pub fn format_mem(bytes: &[u8]) -> String {
+    bytes
+        .chunks(2)
+        .map(|chunk| hex::encode(chunk) + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn syntax_error(result: Result<Image, LoaderError>) -> (usize, usize) {
+        match result {
+            Err(LoaderError::Syntax(line, column, _)) => (line, column),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_mem_test() {
+        let bytes = parse_mem("3141 0102\nff00\n").unwrap();
+        assert_eq!(bytes, vec![0x31, 0x41, 0x01, 0x02, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn format_mem_test() {
+        let bytes = vec![0x31, 0x41, 0x01, 0x02, 0xff];
+        assert_eq!(format_mem(&bytes), "3141\n0102\nff\n");
+        assert_eq!(parse_mem(&format_mem(&bytes)).unwrap(), bytes);
+    }
+
+    #[test]
+    fn parse_mem_invalid_hex() {
+        assert!(parse_mem("zz").is_err());
+        let result = parse_image(b"3141\n0102  zz01\n", Format::Mem);
+        assert_eq!(syntax_error(result), (2, 7));
+    }
+
+    #[test]
+    fn parse_intel_hex_test() {
+        let text = ":0400000001020304F2\n:02000004000FEB\n:01B00000054A\n:00000001FF\n";
+        let image = parse_intel_hex(text).unwrap_err();
+        // 0x000fb000 is beyond the address space
+        assert!(matches!(image, LoaderError::Syntax(3, 4, _)));
+
+        let text =
+            ":0400000001020304F2\n:020000040000FA\n:02B00000050643\n:02B0020007083D\n:00000001FF\n";
+        let image = parse_intel_hex(text).unwrap();
+        assert!(image.absolute);
+        assert_eq!(
+            image.segments,
+            vec![
+                Segment {
+                    address: 0x0000,
+                    bytes: vec![1, 2, 3, 4]
+                },
+                Segment {
+                    address: 0xb000,
+                    bytes: vec![5, 6, 7, 8]
+                },
+            ]
+        );
+        assert_eq!(detect(text.as_bytes()), Format::IntelHex);
+
+        assert_eq!(
+            syntax_error(parse_intel_hex(":0400000001020304F3\n")),
+            (1, 18)
+        );
+        assert_eq!(
+            syntax_error(parse_intel_hex("\n:04000000010203G4F2\n")),
+            (2, 16)
+        );
+        assert_eq!(
+            syntax_error(parse_intel_hex(":0500000001020304F2\n")),
+            (1, 2)
+        );
+        assert_eq!(
+            syntax_error(parse_intel_hex("0400000001020304F2\n")),
+            (1, 1)
+        );
+        assert_eq!(
+            syntax_error(parse_intel_hex(":02000004FFFFFC\n:02FFFE000102FE\n")),
+            (2, 4)
+        );
+    }
+
+    #[test]
+    fn parse_srecord_test() {
+        let text = "S00600004844521B\nS107B000010203043E\nS207000010050607D6\nS9030000FC\n";
+        let image = parse_srecord(text).unwrap();
+        assert_eq!(
+            image.segments,
+            vec![
+                Segment {
+                    address: 0xb000,
+                    bytes: vec![1, 2, 3, 4]
+                },
+                Segment {
+                    address: 0x0010,
+                    bytes: vec![5, 6, 7]
+                },
+            ]
+        );
+        assert_eq!(detect(text.as_bytes()), Format::SRecord);
+
+        assert_eq!(syntax_error(parse_srecord("S107B00001020304F4\n")), (1, 17));
+        assert_eq!(syntax_error(parse_srecord("S107B0000102030XF5\n")), (1, 16));
+        assert_eq!(syntax_error(parse_srecord("S4030000FC\n")), (1, 2));
+        assert_eq!(
+            syntax_error(parse_srecord("S3090001000001020304EB\n")),
+            (1, 5)
+        );
+        assert_eq!(
+            syntax_error(parse_srecord("S309FFFFFFFF01020304F0\n")),
+            (1, 5)
+        );
+    }
+
+    #[test]
+    fn detect_and_place() {
+        assert_eq!(detect(b"  3141\n0102\n"), Format::Mem);
+        assert_eq!(detect(&[0x31, 0x41, 0x00, 0xff]), Format::Binary);
+        assert_eq!(detect(b"hello"), Format::Binary);
+        assert_eq!("srec".parse(), Ok(Format::SRecord));
+
+        let image = parse_image(&[1, 2, 3], Format::Binary).unwrap();
+        assert_eq!(image.region_bytes(0x9000, 4).unwrap(), vec![1, 2, 3]);
+        assert!(image.region_bytes(0x9000, 2).is_err());
+        assert_eq!(
+            image.placed(0x9000).collect::<Vec<_>>(),
+            vec![(0x9000, &[1u8, 2, 3][..])]
+        );
+
+        let image = parse_srecord("S105B004010243\n").unwrap();
+        assert_eq!(
+            image.region_bytes(0xb000, 8).unwrap(),
+            vec![0, 0, 0, 0, 1, 2]
+        );
+        assert!(image.region_bytes(0xb005, 8).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+use super::bus::Permissions;
+use super::cpu::PsrLayout;
+use super::loader::{self, Image, LoaderError};
+use super::memory::{RAM_LOW_ADDRESS, RAM_SIZE, ROM_LOW_ADDRESS, ROM_SIZE};
+use super::{intc, uart};
+use serde::Deserialize;
+use std::path::Path;
+
+// A board : the regions of the address space, where the cpu starts after reset and where
+// its psr bits are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Machine {
+    pub reset_pc: u16,
+    pub regions: Vec<RegionSpec>,
+    pub psr: PsrLayout,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionSpec {
+    pub name: String,
+    pub base: u16,
+    pub size: u16,
+    pub kind: RegionKind,
+    // placed at the base, the rest of the region is zero
+    pub image: Vec<u8>,
+    pub permissions: Permissions,
+    // interrupt controller line of a device
+    pub irq: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Rom,
+    Ram,
+    Device(DeviceKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Intc,
+    // mapped by the caller, which knows where the uart is connected
+    Uart,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MachineError {
+    #[error("could not read machine description '{0}'")]
+    Io(String, #[source] std::io::Error),
+
+    #[error("could not parse machine description : {0}")]
+    Parse(String),
+
+    #[error("region '{0}' : {1}")]
+    InvalidRegion(String, String),
+
+    #[error("could not load the image of region '{0}'")]
+    Image(String, #[source] LoaderError),
+
+    #[error("no {0} region")]
+    NoRegion(&'static str),
+
+    #[error("no reset_pc and no rom region to start from")]
+    NoResetPc(),
+
+    #[error("psr bits overlap or do not fit in 16 bits")]
+    InvalidPsr(),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+// The file format. Numbers are integers or strings such as "0xb000", since JSON has no hex.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    reset_pc: Option<Number>,
+    regions: Vec<RegionConfig>,
+    psr: Option<PsrConfig>,
+}
+
+// bit numbers, the cause field is 4 bits from cause_shift
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PsrConfig {
+    interrupt_enable: u8,
+    user_mode: u8,
+    cause_shift: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegionConfig {
+    name: String,
+    base: Number,
+    size: Option<Number>,
+    kind: KindConfig,
+    device: Option<String>,
+    image: Option<String>,
+    permissions: Option<String>,
+    irq: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum KindConfig {
+    Rom,
+    Ram,
+    Device,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Int(u64),
+    Str(String),
+}
+
+impl Number {
+    fn to_u32(&self) -> Option<u32> {
+        match self {
+            Number::Int(n) => u32::try_from(*n).ok(),
+            Number::Str(s) => match s.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => s.parse().ok(),
+            },
+        }
+    }
+}
+
+// The board without a machine description.
+impl Default for Machine {
+    fn default() -> Self {
+        let region = |name: &str, base, size, kind, permissions: &str| RegionSpec {
+            name: name.to_string(),
+            base,
+            size,
+            kind,
+            image: vec![],
+            permissions: permissions.parse().unwrap(),
+            irq: None,
+        };
+        Machine {
+            reset_pc: ROM_LOW_ADDRESS,
+            regions: vec![
+                region("ram", RAM_LOW_ADDRESS, RAM_SIZE, RegionKind::Ram, "rwx"),
+                region("rom", ROM_LOW_ADDRESS, ROM_SIZE, RegionKind::Rom, "rx"),
+                region(
+                    "intc",
+                    intc::DEFAULT_BASE,
+                    intc::SIZE,
+                    RegionKind::Device(DeviceKind::Intc),
+                    "rw",
+                ),
+            ],
+            psr: PsrLayout::default(),
+        }
+    }
+}
+
+impl RegionKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Rom => "rom",
+            RegionKind::Ram => "ram",
+            RegionKind::Device(DeviceKind::Intc) => "intc",
+            RegionKind::Device(DeviceKind::Uart) => "uart",
+        }
+    }
+}
+
+impl DeviceKind {
+    pub fn size(&self) -> u16 {
+        match self {
+            DeviceKind::Intc => intc::SIZE,
+            DeviceKind::Uart => uart::SIZE,
+        }
+    }
+}
+
+impl Machine {
+    // The format is taken from the extension, .json is JSON and anything else TOML. Image
+    // paths are relative to the directory of the description.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MachineError> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| MachineError::Io(path.display().to_string(), e))?;
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        };
+        let dir = path.parent().unwrap_or(Path::new("."));
+        Self::parse(&src, format, |image| {
+            loader::load_image(dir.join(image), None)
+        })
+    }
+
+    // load_image reads the image file named by a region, whose format is detected from its
+    // content.
+    pub fn parse<F>(src: &str, format: Format, mut load_image: F) -> Result<Self, MachineError>
+    where
+        F: FnMut(&str) -> Result<Image, LoaderError>,
+    {
+        let config: Config = match format {
+            Format::Toml => toml::from_str(src).map_err(|e| MachineError::Parse(e.to_string()))?,
+            Format::Json => {
+                serde_json::from_str(src).map_err(|e| MachineError::Parse(e.to_string()))?
+            }
+        };
+
+        let mut regions = vec![];
+        for region in config.regions {
+            let invalid =
+                |why: &str| MachineError::InvalidRegion(region.name.clone(), why.to_string());
+            let base = region
+                .base
+                .to_u32()
+                .and_then(|base| u16::try_from(base).ok())
+                .ok_or_else(|| invalid("base is not an address"))?;
+            let size = region.size.as_ref().map(|size| size.to_u32());
+            let kind = match (region.kind, region.device.as_deref()) {
+                (KindConfig::Rom, None) => RegionKind::Rom,
+                (KindConfig::Ram, None) => RegionKind::Ram,
+                (KindConfig::Device, Some("intc")) => RegionKind::Device(DeviceKind::Intc),
+                (KindConfig::Device, Some("uart")) => RegionKind::Device(DeviceKind::Uart),
+                (KindConfig::Device, Some(_)) => Err(invalid("device is intc or uart"))?,
+                (KindConfig::Device, None) => Err(invalid("no device"))?,
+                (_, Some(_)) => Err(invalid("only device regions have a device"))?,
+            };
+            let size = match (kind, size) {
+                (RegionKind::Device(device), None) => device.size() as u32,
+                (RegionKind::Device(device), Some(Some(size))) if size != device.size() as u32 => {
+                    Err(invalid(&format!(
+                        "{} has a size of {}",
+                        kind.name(),
+                        device.size()
+                    )))?
+                }
+                (_, Some(Some(size))) => size,
+                (_, Some(None)) => Err(invalid("size is not a number"))?,
+                (_, None) => Err(invalid("no size"))?,
+            };
+            if size == 0 || size > u16::MAX as u32 || base as u32 + size > 0x10000 {
+                Err(invalid("does not fit in the address space"))?
+            }
+            let image = match (&region.image, kind) {
+                (None, _) => vec![],
+                (Some(_), RegionKind::Device(_)) => Err(invalid("devices have no image"))?,
+                (Some(path), _) => load_image(path)
+                    .and_then(|image| image.region_bytes(base, size as u16))
+                    .map_err(|e| MachineError::Image(region.name.clone(), e))?,
+            };
+            let permissions = match &region.permissions {
+                Some(permissions) => permissions.parse().map_err(|e: String| invalid(&e))?,
+                None => match kind {
+                    RegionKind::Rom => "rx".parse().unwrap(),
+                    RegionKind::Ram => Permissions::ALL,
+                    RegionKind::Device(_) => "rw".parse().unwrap(),
+                },
+            };
+            regions.push(RegionSpec {
+                name: region.name,
+                base,
+                size: size as u16,
+                kind,
+                image,
+                permissions,
+                irq: region.irq,
+            });
+        }
+
+        let reset_pc = match config.reset_pc {
+            Some(pc) => pc
+                .to_u32()
+                .and_then(|pc| u16::try_from(pc).ok())
+                .ok_or_else(|| MachineError::Parse("reset_pc is not an address".to_string()))?,
+            None => regions
+                .iter()
+                .find(|r| r.kind == RegionKind::Rom)
+                .map(|r| r.base)
+                .ok_or(MachineError::NoResetPc())?,
+        };
+        let psr = match config.psr {
+            Some(psr) => PsrLayout::from_bits(psr.interrupt_enable, psr.user_mode, psr.cause_shift)
+                .ok_or(MachineError::InvalidPsr())?,
+            None => PsrLayout::default(),
+        };
+        Ok(Machine {
+            reset_pc,
+            regions,
+            psr,
+        })
+    }
+
+    // Replaces the image of the first region of the kind, e.g. with the rom file given on
+    // the command line. A relative image is placed at the base of the region.
+    pub fn set_image(&mut self, kind: RegionKind, image: &Image) -> Result<(), MachineError> {
+        let region = self
+            .regions
+            .iter_mut()
+            .find(|r| r.kind == kind)
+            .ok_or(MachineError::NoRegion(kind.name()))?;
+        region.image = image
+            .region_bytes(region.base, region.size)
+            .map_err(|e| MachineError::Image(region.name.clone(), e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::zktc::cpu::{self, Exception};
+
+    fn no_image(_: &str) -> Result<Image, LoaderError> {
+        Ok(Image::default())
+    }
+
+    #[test]
+    fn parse_toml() {
+        let src = r#"
+            reset_pc = 0x4000
+
+            [[regions]]
+            name = "ram"
+            base = 0x0000
+            size = 0x4000
+            kind = "ram"
+
+            [[regions]]
+            name = "rom"
+            base = 0x4000
+            size = 0x2000
+            kind = "rom"
+            image = "boot.mem"
+
+            [[regions]]
+            name = "serial"
+            base = 0x9000
+            kind = "device"
+            device = "uart"
+            irq = 1
+        "#;
+        let machine = Machine::parse(src, Format::Toml, |path| {
+            assert_eq!(path, "boot.mem");
+            Ok(Image::relative(vec![0x12, 0x34]))
+        })
+        .unwrap();
+        assert_eq!(machine.reset_pc, 0x4000);
+        assert_eq!(machine.regions.len(), 3);
+        assert_eq!(machine.regions[1].image, vec![0x12, 0x34]);
+        assert_eq!(machine.regions[1].permissions, "rx".parse().unwrap());
+        assert_eq!(machine.regions[2].size, uart::SIZE);
+        assert_eq!(
+            machine.regions[2].kind,
+            RegionKind::Device(DeviceKind::Uart)
+        );
+        assert_eq!(machine.regions[2].irq, Some(1));
+    }
+
+    #[test]
+    fn psr_layout() {
+        let parse = |psr: &str| {
+            let src = format!(
+                "{}\n[[regions]]\nname = \"rom\"\nbase = 0\nsize = 2\nkind = \"rom\"",
+                psr
+            );
+            Machine::parse(&src, Format::Toml, no_image).map(|m| m.psr)
+        };
+        assert_eq!(parse("").unwrap(), PsrLayout::default());
+        let psr = parse("[psr]\ninterrupt_enable = 0\nuser_mode = 4\ncause_shift = 12").unwrap();
+        assert_eq!((psr.interrupt_enable, psr.user_mode), (0x0001, 0x0010));
+        assert_eq!(psr.cause_mask(), 0xf000);
+        assert_eq!(psr.trap(Exception::Interrupt), 0x1004);
+        assert_eq!(PsrLayout::default().trap(Exception::Trap), cpu::PSR_TRAP);
+        assert!(parse("[psr]\ninterrupt_enable = 9\nuser_mode = 3\ncause_shift = 8").is_err());
+        assert!(parse("[psr]\ninterrupt_enable = 1\nuser_mode = 1\ncause_shift = 8").is_err());
+        assert!(parse("[psr]\ninterrupt_enable = 1\nuser_mode = 3\ncause_shift = 13").is_err());
+    }
+
+    #[test]
+    fn parse_json() {
+        let src = r#"{
+            "regions": [
+                { "name": "rom", "base": "0xc000", "size": 16384, "kind": "rom", "permissions": "rwx" }
+            ]
+        }"#;
+        let machine = Machine::parse(src, Format::Json, no_image).unwrap();
+        assert_eq!(machine.reset_pc, 0xc000);
+        assert_eq!(machine.regions[0].size, 0x4000);
+        assert_eq!(machine.regions[0].permissions, Permissions::ALL);
+    }
+
+    #[test]
+    fn invalid_regions() {
+        let parse = |region: &str| {
+            let src = format!("[[regions]]\nname = \"r\"\n{}", region);
+            Machine::parse(&src, Format::Toml, no_image).map(|_| ())
+        };
+        assert!(parse("base = 0xf000\nsize = 0x2000\nkind = \"rom\"").is_err());
+        assert!(parse("base = 0x10000\nsize = 1\nkind = \"ram\"").is_err());
+        assert!(parse("base = 0\nkind = \"ram\"").is_err());
+        assert!(parse("base = 0\nkind = \"device\"\ndevice = \"gpu\"").is_err());
+        assert!(parse("base = 0\nsize = 2\nkind = \"device\"\ndevice = \"intc\"").is_err());
+        assert!(parse("base = 0\nsize = 2\nkind = \"ram\"\npermissions = \"rq\"").is_err());
+        assert!(parse("base = 0\nsize = 2\nkind = \"ram\"\nbus = 1").is_err());
+        // no rom to start from
+        assert!(parse("base = 0\nsize = 2\nkind = \"ram\"").is_err());
+    }
+
+    #[test]
+    fn set_image() {
+        let mut machine = Machine::default();
+        machine
+            .set_image(RegionKind::Rom, &Image::relative(vec![1, 2]))
+            .unwrap();
+        assert_eq!(machine.regions[1].image, vec![1, 2]);
+        assert!(machine
+            .set_image(
+                RegionKind::Ram,
+                &Image::relative(vec![0; RAM_SIZE as usize + 1])
+            )
+            .is_err());
+        // an image with addresses is placed at them
+        let image = loader::parse_srecord("S105B004010243\n").unwrap();
+        machine.set_image(RegionKind::Rom, &image).unwrap();
+        assert_eq!(machine.regions[1].image, vec![0, 0, 0, 0, 1, 2]);
+        assert!(machine.set_image(RegionKind::Ram, &image).is_err());
+    }
+}
@@ -1,7 +1,28 @@
+use super::bus::{Bus, BusError, Device, DeviceStates, Permissions, Ram};
+use super::intc::InterruptController;
+use super::loader::Image;
+use super::machine::{DeviceKind, Machine, RegionKind};
+
+// The address space seen by the cpu, ROM, RAM and the interrupt controller are devices on the
+// bus, laid out as described by a Machine.
 #[derive(Debug)]
 pub struct Memory {
-    rom: Vec<u8>,
-    ram: Vec<u8>,
+    bus: Bus,
+    discard_read_only_writes: bool,
+    alignment: Alignment,
+}
+
+// What a word load or store at an odd address does. Words are little endian and may cross the
+// end of a region, the debugger always reads and writes the two bytes at the address.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Alignment {
+    // fails with MemoryError::Misaligned
+    #[default]
+    Fault,
+    // accesses the byte at the address and the next one
+    Split,
+    // ignores the low bit of the address
+    ForceAlign,
 }
 
 pub const ROM_HIGH_ADDRESS: u16 = 0xFFFF;
@@ -12,6 +33,31 @@ pub const RAM_HIGH_ADDRESS: u16 = 0x8000;
 pub const RAM_LOW_ADDRESS: u16 = 0x0;
 pub const RAM_SIZE: u16 = (RAM_HIGH_ADDRESS - RAM_LOW_ADDRESS) + 1;
 
+// The word test programs write their result to, see run --status.
+pub const STATUS_ADDRESS: u16 = 0xFFFE;
+
+// Peripherals are mapped between RAM and ROM.
+pub const IO_LOW_ADDRESS: u16 = 0x8001;
+pub const IO_HIGH_ADDRESS: u16 = 0xAFFF;
+
+// A load or store made by an instruction. half is a byte access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub address: u16,
+    pub half: bool,
+    pub write: bool,
+}
+
+impl MemoryAccess {
+    pub fn size(&self) -> u16 {
+        if self.half {
+            1
+        } else {
+            2
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug, PartialEq)]
 pub enum MemoryError {
     #[error("rom file is too large")]
@@ -22,90 +68,279 @@ pub enum MemoryError {
 
     #[error("address 0x{0:04x} is out of range")]
     InvalidAddress(u16),
+
+    #[error("address 0x{0:04x} is misaligned")]
+    Misaligned(u16),
+
+    #[error("address 0x{0:04x} is not readable")]
+    NotReadable(u16),
+
+    #[error("address 0x{0:04x} is not writable")]
+    NotWritable(u16),
+
+    #[error("address 0x{0:04x} is not executable")]
+    NotExecutable(u16),
+
+    #[error(transparent)]
+    Bus(BusError),
+}
+
+impl From<BusError> for MemoryError {
+    fn from(e: BusError) -> Self {
+        match e {
+            BusError::Unmapped(address) => MemoryError::InvalidAddress(address),
+            e => MemoryError::Bus(e),
+        }
+    }
 }
 
 impl Memory {
     pub fn new(rom_file: Vec<u8>, ram_file: Vec<u8>) -> Result<Self, MemoryError> {
-        let mut memory = Memory {
-            rom: rom_file,
-            ram: ram_file,
-        };
-        if (ROM_SIZE as usize) < memory.rom.len() {
+        if (ROM_SIZE as usize) < rom_file.len() {
             Err(MemoryError::TooLargeRomFile())?
         }
-        if (RAM_SIZE as usize) < memory.ram.len() {
+        if (RAM_SIZE as usize) < ram_file.len() {
             Err(MemoryError::TooLargeRamFile())?
         }
 
-        for _i in 0..((ROM_SIZE as usize) - memory.rom.len()) {
-            memory.rom.push(0);
+        let mut machine = Machine::default();
+        for (name, image) in [("rom", rom_file), ("ram", ram_file)] {
+            if let Some(region) = machine.regions.iter_mut().find(|r| r.name == name) {
+                region.image = image;
+            }
         }
-        for _i in 0..((RAM_SIZE as usize) - memory.ram.len()) {
-            memory.ram.push(0);
+        Self::from_machine(&machine)
+    }
+
+    // Uart regions are left to the caller, which knows where the uart is connected.
+    pub fn from_machine(machine: &Machine) -> Result<Self, MemoryError> {
+        let mut bus = Bus::new();
+        for region in machine.regions.iter() {
+            let device: Box<dyn Device> = match region.kind {
+                RegionKind::Rom | RegionKind::Ram => Box::new(Ram::new(region.size, &region.image)),
+                RegionKind::Device(DeviceKind::Intc) => Box::new(InterruptController::new()),
+                RegionKind::Device(DeviceKind::Uart) => continue,
+            };
+            bus.map(&region.name, region.base, region.size, device)?;
+            bus.set_permissions(&region.name, region.permissions)?;
+            if let Some(line) = region.irq {
+                bus.connect_irq(&region.name, line)?;
+            }
         }
-        Ok(memory)
+        Ok(Memory {
+            bus,
+            discard_read_only_writes: false,
+            alignment: Alignment::default(),
+        })
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    // Adds a peripheral, e.g. at IO_LOW_ADDRESS.
+    pub fn map(
+        &mut self,
+        name: &str,
+        base: u16,
+        size: u16,
+        device: Box<dyn Device>,
+    ) -> Result<(), MemoryError> {
+        Ok(self.bus.map(name, base, size, device)?)
+    }
+
+    pub fn device(&self, name: &str) -> Option<&dyn Device> {
+        self.bus.device(name)
     }
 
+    pub fn device_mut(&mut self, name: &str) -> Option<&mut (dyn Device + 'static)> {
+        self.bus.device_mut(name)
+    }
+
+    // Reads without side effects on devices, for the debugger and instruction fetch.
     pub fn read_from_memory(&self, address: &u16, half: bool) -> Result<u16, MemoryError> {
-        let mut data = if (ROM_LOW_ADDRESS..=ROM_HIGH_ADDRESS - 1).contains(address) {
-            self.read_from_rom(&(address - ROM_LOW_ADDRESS))
-        } else if (RAM_LOW_ADDRESS..=RAM_HIGH_ADDRESS - 1).contains(address) {
-            self.read_from_ram(&(address - RAM_LOW_ADDRESS))
+        if half {
+            Ok(self.bus.peek8(*address)? as i8 as u16) // sign extention
         } else {
-            Err(MemoryError::InvalidAddress(*address))?
-        };
+            Ok(self.bus.peek16(*address)?)
+        }
+    }
+
+    // A read made by a load instruction.
+    pub fn load(&mut self, address: &u16, half: bool) -> Result<u16, MemoryError> {
+        let access = self.align(MemoryAccess {
+            address: *address,
+            half,
+            write: false,
+        });
+        self.check(&access)?;
         if half {
-            data = ((data & 0x00ff) as i8) as u16; // sign extention
+            Ok(self.bus.read8(access.address)? as i8 as u16) // sign extention
+        } else {
+            Ok(self.bus.read16(access.address)?)
+        }
+    }
+
+    // An instruction fetch.
+    pub fn fetch(&self, pc: u16) -> Result<u16, MemoryError> {
+        for address in [pc, pc.wrapping_add(1)] {
+            if !self.permissions(address).execute {
+                Err(MemoryError::NotExecutable(address))?
+            }
+        }
+        Ok(self.bus.peek16(pc)?)
+    }
+
+    pub fn set_alignment(&mut self, alignment: Alignment) {
+        self.alignment = alignment;
+    }
+
+    pub fn alignment(&self) -> Alignment {
+        self.alignment
+    }
+
+    // The access an instruction actually makes for access.
+    pub fn align(&self, access: MemoryAccess) -> MemoryAccess {
+        match self.alignment {
+            Alignment::ForceAlign if !access.half => MemoryAccess {
+                address: access.address & !1,
+                ..access
+            },
+            _ => access,
         }
-        Ok(data)
     }
+
+    // Whether an instruction may make the access, the debugger may make any. Both bytes of a
+    // word need the permission.
+    pub fn check(&self, access: &MemoryAccess) -> Result<(), MemoryError> {
+        if !access.half && access.address & 1 != 0 && self.alignment == Alignment::Fault {
+            Err(MemoryError::Misaligned(access.address))?
+        }
+        for offset in 0..access.size() {
+            let address = access.address.wrapping_add(offset);
+            let permissions = self.permissions(address);
+            match access.write {
+                true if !permissions.write => Err(MemoryError::NotWritable(address))?,
+                false if !permissions.read => Err(MemoryError::NotReadable(address))?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Unmapped addresses have all permissions, accessing them fails anyway.
+    fn permissions(&self, address: u16) -> Permissions {
+        self.bus.permissions(address).unwrap_or(Permissions::ALL)
+    }
+
     pub fn write_to_memory(
         &mut self,
         address: &u16,
-        mut data: u16,
+        data: u16,
         half: bool,
     ) -> Result<(), MemoryError> {
         if half {
-            data &= 0x00ff;
+            self.bus.write8(*address, (data & 0x00ff) as u8)?;
+        } else {
+            self.bus.write16(*address, data)?;
         }
-        if (ROM_LOW_ADDRESS..=ROM_HIGH_ADDRESS - 1).contains(address) {
-            if half {
-                data |= self.read_from_rom(&(address - ROM_LOW_ADDRESS)) & 0xff00;
-            }
-            self.write_to_rom(&(address - ROM_LOW_ADDRESS), data);
-        } else if (RAM_LOW_ADDRESS..=RAM_HIGH_ADDRESS - 1).contains(address) {
-            if half {
-                data |= self.read_from_ram(&(address - RAM_LOW_ADDRESS)) & 0xff00;
+        Ok(())
+    }
+
+    // A write made by a store instruction.
+    pub fn store(&mut self, address: &u16, data: u16, half: bool) -> Result<(), MemoryError> {
+        let access = self.align(MemoryAccess {
+            address: *address,
+            half,
+            write: true,
+        });
+        match self.check(&access) {
+            Err(MemoryError::NotWritable(_)) if self.discard_read_only_writes => Ok(()),
+            Err(e) => Err(e),
+            Ok(()) => self.write_to_memory(&access.address, data, half),
+        }
+    }
+
+    // Whether stores to regions without the write permission are dropped instead of failing.
+    pub fn set_discard_read_only_writes(&mut self, discard: bool) {
+        self.discard_read_only_writes = discard;
+    }
+
+    // Writes an image as the debugger would, a relative image is placed at base.
+    pub fn write_image(&mut self, image: &Image, base: u16) -> Result<(), MemoryError> {
+        for (address, bytes) in image.placed(base) {
+            for (offset, byte) in bytes.iter().enumerate() {
+                self.bus
+                    .write8(address.wrapping_add(offset as u16), *byte)?;
             }
-            self.write_to_ram(&(address - RAM_LOW_ADDRESS), data);
-        } else {
-            Err(MemoryError::InvalidAddress(*address))?
-        };
+        }
         Ok(())
     }
 
-    fn read_from_rom(&self, address: &u16) -> u16 {
-        ((self.rom[(address + 1) as usize] as u16) << 8) | (self.rom[*address as usize] as u16)
+    // Writes back a value overwritten by a store, only into plain memory. The state of the
+    // other devices is restored with load_devices.
+    pub fn poke(&mut self, address: u16, data: u16, half: bool) -> Result<(), MemoryError> {
+        let [low, high] = data.to_le_bytes();
+        self.bus.poke8(address, low)?;
+        if !half {
+            self.bus.poke8(address.wrapping_add(1), high)?;
+        }
+        Ok(())
+    }
+
+    pub fn record_devices(&mut self, on: bool) {
+        self.bus.record_devices(on);
+    }
+
+    pub fn take_devices(&mut self) -> DeviceStates {
+        self.bus.take_devices()
+    }
+
+    pub fn load_devices(&mut self, states: &DeviceStates) {
+        self.bus.load_devices(states);
+    }
+
+    pub fn read_byte(&self, address: u16) -> Result<u8, MemoryError> {
+        Ok(self.bus.peek8(address)?)
+    }
+
+    pub fn write_byte(&mut self, address: u16, data: u8) -> Result<(), MemoryError> {
+        Ok(self.bus.write8(address, data)?)
+    }
+
+    pub fn tick(&mut self) {
+        self.bus.tick();
+    }
+
+    pub fn reset(&mut self) {
+        self.bus.reset();
+    }
+
+    pub fn set_permissions(
+        &mut self,
+        name: &str,
+        permissions: Permissions,
+    ) -> Result<(), MemoryError> {
+        Ok(self.bus.set_permissions(name, permissions)?)
     }
 
-    fn read_from_ram(&self, address: &u16) -> u16 {
-        ((self.ram[(address + 1) as usize] as u16) << 8) | (self.ram[*address as usize] as u16)
+    pub fn connect_irq(&mut self, name: &str, line: u8) -> Result<(), MemoryError> {
+        Ok(self.bus.connect_irq(name, line)?)
     }
 
-    pub fn write_to_rom(&mut self, address: &u16, data: u16) {
-        self.rom[*(address) as usize] = (data & 0x00FF) as u8;
-        self.rom[(address + 1) as usize] = ((data & 0xFF00) >> 8) as u8;
+    pub fn raise_irq(&mut self, lines: u16) {
+        self.bus.raise(lines);
     }
 
-    pub fn write_to_ram(&mut self, address: &u16, data: u16) {
-        self.ram[*(address) as usize] = (data & 0x00FF) as u8;
-        self.ram[(address + 1) as usize] = ((data & 0xFF00) >> 8) as u8;
+    pub fn irq(&self) -> bool {
+        self.bus.irq()
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::zktc::intc;
 
     #[test]
     #[should_panic]
@@ -122,4 +357,146 @@ mod test {
         let ram_file: Vec<u8> = vec![0; (RAM_SIZE + 1) as usize];
         Memory::new(rom_file, ram_file).unwrap();
     }
+
+    #[test]
+    fn region_edges() {
+        let rom: Vec<u8> = (0..ROM_SIZE).map(|i| i as u8).collect();
+        let mut memory = Memory::new(rom, vec![]).unwrap();
+
+        // the first and last byte of every region and the bytes around them, the pending
+        // register of the interrupt controller is cleared by writes so its mask is written
+        let intc = (intc::DEFAULT_BASE, intc::DEFAULT_BASE + (intc::SIZE - 1));
+        for (low, high) in [
+            (RAM_LOW_ADDRESS, RAM_HIGH_ADDRESS),
+            intc,
+            (ROM_LOW_ADDRESS, ROM_HIGH_ADDRESS),
+        ] {
+            let first = match (low, high) == intc {
+                true => low + intc::MASK,
+                false => low,
+            };
+            for address in [first, high] {
+                memory.write_to_memory(&address, 0x5a, true).unwrap();
+                assert_eq!(memory.read_byte(address), Ok(0x5a));
+            }
+            if let Some(before) = low.checked_sub(1) {
+                assert_eq!(
+                    memory.read_byte(before),
+                    Err(MemoryError::InvalidAddress(before))
+                );
+            }
+            if let Some(after) = high.checked_add(1) {
+                assert_eq!(
+                    memory.read_byte(after),
+                    Err(MemoryError::InvalidAddress(after))
+                );
+            }
+        }
+        // words crossing the end of a region, the address space wraps
+        assert_eq!(
+            memory.read_from_memory(&RAM_HIGH_ADDRESS, false),
+            Err(MemoryError::InvalidAddress(RAM_HIGH_ADDRESS + 1))
+        );
+        assert_eq!(
+            memory.read_from_memory(&(ROM_LOW_ADDRESS - 1), false),
+            Err(MemoryError::InvalidAddress(ROM_LOW_ADDRESS - 1))
+        );
+        assert_eq!(
+            memory.read_from_memory(&ROM_HIGH_ADDRESS, false),
+            Ok(0x5a5a)
+        );
+        assert_eq!(memory.fetch(ROM_HIGH_ADDRESS - 1), Ok(0x5afe));
+
+        memory.set_alignment(Alignment::Split);
+        assert_eq!(memory.load(&ROM_HIGH_ADDRESS, false), Ok(0x5a5a));
+        assert_eq!(
+            memory.store(&ROM_HIGH_ADDRESS, 0x1234, false),
+            Err(MemoryError::NotWritable(ROM_HIGH_ADDRESS))
+        );
+    }
+
+    #[test]
+    fn misaligned_words() {
+        let mut memory = Memory::new(vec![], vec![0x11, 0x22, 0x33, 0x44]).unwrap();
+        assert_eq!(memory.load(&1, false), Err(MemoryError::Misaligned(1)));
+        assert_eq!(memory.store(&1, 0, false), Err(MemoryError::Misaligned(1)));
+        assert_eq!(memory.load(&1, true), Ok(0x22));
+
+        memory.set_alignment(Alignment::Split);
+        assert_eq!(memory.load(&1, false), Ok(0x3322));
+        memory.store(&1, 0xaabb, false).unwrap();
+        assert_eq!(memory.read_from_memory(&0, false), Ok(0xbb11));
+        assert_eq!(memory.read_from_memory(&2, false), Ok(0x44aa));
+
+        memory.set_alignment(Alignment::ForceAlign);
+        assert_eq!(memory.load(&3, false), Ok(0x44aa));
+        memory.store(&1, 0x5566, false).unwrap();
+        assert_eq!(memory.read_from_memory(&0, false), Ok(0x5566));
+        let access = MemoryAccess {
+            address: 3,
+            half: false,
+            write: false,
+        };
+        assert_eq!(memory.align(access).address, 2);
+    }
+
+    #[test]
+    fn write_images() {
+        let mut memory = Memory::new(vec![], vec![]).unwrap();
+        memory
+            .write_image(&Image::relative(vec![1, 2, 3]), 0x0100)
+            .unwrap();
+        assert_eq!(memory.read_from_memory(&0x0101, false), Ok(0x0302));
+        let image = crate::zktc::loader::parse_srecord("S105B004010243\n").unwrap();
+        memory.write_image(&image, 0x0100).unwrap();
+        assert_eq!(memory.read_from_memory(&0xb004, false), Ok(0x0201));
+        assert_eq!(
+            memory.write_image(&Image::relative(vec![1, 2]), RAM_HIGH_ADDRESS),
+            Err(MemoryError::InvalidAddress(RAM_HIGH_ADDRESS + 1))
+        );
+    }
+
+    #[test]
+    fn rom_is_read_only() {
+        let rom_file: Vec<u8> = vec![0; ROM_SIZE as usize];
+        let mut memory = Memory::new(rom_file, vec![]).unwrap();
+        assert_eq!(
+            memory.store(&ROM_HIGH_ADDRESS, 1, true),
+            Err(MemoryError::NotWritable(ROM_HIGH_ADDRESS))
+        );
+        assert_eq!(
+            memory.store(&STATUS_ADDRESS, 1, false),
+            Err(MemoryError::NotWritable(STATUS_ADDRESS))
+        );
+    }
+
+    #[test]
+    fn instructions_follow_permissions() {
+        let mut machine = Machine::default();
+        machine.regions[0].permissions = "rw".parse().unwrap();
+        machine.regions[1].permissions = "x".parse().unwrap();
+        let mut memory = Memory::from_machine(&machine).unwrap();
+
+        assert_eq!(
+            memory.fetch(RAM_LOW_ADDRESS),
+            Err(MemoryError::NotExecutable(RAM_LOW_ADDRESS))
+        );
+        assert_eq!(memory.fetch(ROM_LOW_ADDRESS), Ok(0));
+        assert_eq!(
+            memory.store(&ROM_LOW_ADDRESS, 1, false),
+            Err(MemoryError::NotWritable(ROM_LOW_ADDRESS))
+        );
+        assert_eq!(
+            memory.load(&ROM_LOW_ADDRESS, true),
+            Err(MemoryError::NotReadable(ROM_LOW_ADDRESS))
+        );
+        // the debugger is not restricted
+        memory.write_to_memory(&ROM_LOW_ADDRESS, 1, false).unwrap();
+        assert_eq!(memory.read_from_memory(&ROM_LOW_ADDRESS, false), Ok(1));
+        memory.set_discard_read_only_writes(true);
+        memory.store(&ROM_LOW_ADDRESS, 3, false).unwrap();
+        assert_eq!(memory.read_from_memory(&ROM_LOW_ADDRESS, false), Ok(1));
+        memory.store(&RAM_LOW_ADDRESS, 2, false).unwrap();
+        assert_eq!(memory.load(&RAM_LOW_ADDRESS, false), Ok(2));
+    }
 }
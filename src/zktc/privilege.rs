@@ -0,0 +1,80 @@
+use super::inst::Instruction;
+use super::memory::MemoryAccess;
+
+// Strict mode : privileged instructions executed in user mode and user mode loads and stores
+// to a protected region enter the trap handler instead of executing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Protection {
+    regions: Vec<Region>,
+}
+
+// first and last address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub low: u16,
+    pub high: u16,
+}
+
+impl Protection {
+    pub fn new(regions: Vec<Region>) -> Self {
+        Protection { regions }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn is_protected(&self, access: &MemoryAccess) -> bool {
+        let low = access.address;
+        let high = access.address.saturating_add(access.size() - 1);
+        self.regions.iter().any(|r| r.low <= high && low <= r.high)
+    }
+}
+
+impl Region {
+    // "0x0000-0x0fff"
+    pub fn parse(s: &str) -> Option<Self> {
+        let (low, high) = s.split_once('-')?;
+        let parse = |s: &str| u16::from_str_radix(s.trim().strip_prefix("0x")?, 16).ok();
+        let (low, high) = (parse(low)?, parse(high)?);
+        (low <= high).then_some(Region { low, high })
+    }
+}
+
+// Instructions that change the mode, the trap state, the stack pointer or the timer.
+pub fn is_privileged(inst: &Instruction) -> bool {
+    use Instruction::*;
+
+    matches!(
+        inst,
+        Wsp { .. }
+            | Wpsr { .. }
+            | Wppc { .. }
+            | Wppsr { .. }
+            | Wtlr { .. }
+            | Wthr { .. }
+            | Wtr
+            | Rfi
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protected_regions() {
+        let protection = Protection::new(vec![Region::parse("0x1000-0x1fff").unwrap()]);
+        let access = |address, half| MemoryAccess {
+            address,
+            half,
+            write: false,
+        };
+        assert!(protection.is_protected(&access(0x1000, true)));
+        assert!(protection.is_protected(&access(0x0fff, false)));
+        assert!(!protection.is_protected(&access(0x0fff, true)));
+        assert!(!protection.is_protected(&access(0x2000, false)));
+        assert_eq!(Region::parse("0x2000-0x1000"), None);
+        assert_eq!(Region::parse("1000-2000"), None);
+    }
+}
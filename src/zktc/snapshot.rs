@@ -0,0 +1,321 @@
+use super::breakpoint::{Breakpoint, BreakpointKind, Condition, WatchKind};
+use super::cpu::Cpu;
+use super::timer::TimerState;
+
+// A snapshot starts with MAGIC and a little endian u16 version, followed by sections of a
+// 4 byte tag, a little endian u32 length and the payload. Each device on the bus has a DEVICE
+// section holding a u32 name length, the name and the device state.
+pub const MAGIC: &[u8; 8] = b"ZKTCSNAP";
+pub const VERSION: u16 = 1;
+
+const CPU: &[u8; 4] = b"CPU ";
+const DEVICE: &[u8; 4] = b"DEV ";
+const BREAKPOINTS: &[u8; 4] = b"BRKP";
+const STEPS: &[u8; 4] = b"STEP";
+const TIMER: &[u8; 4] = b"TIMR";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub cpu: Cpu,
+    // device name and state
+    pub devices: Vec<(String, Vec<u8>)>,
+    pub breakpoints: Vec<Breakpoint>,
+    pub steps: u64,
+    pub timer: TimerState,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SnapshotError {
+    #[error("not a zktc snapshot")]
+    BadMagic(),
+
+    #[error("snapshot version {0} is not supported, this emulator reads version {VERSION}")]
+    UnsupportedVersion(u16),
+
+    #[error("snapshot is truncated")]
+    Truncated(),
+
+    #[error("unknown snapshot section '{0}'")]
+    UnknownSection(String),
+
+    #[error("snapshot has no '{0}' section")]
+    MissingSection(String),
+
+    #[error("invalid breakpoint in snapshot : {0}")]
+    InvalidBreakpoint(String),
+
+    #[error("snapshot has state for device '{0}' which this machine does not have")]
+    UnknownDevice(String),
+
+    #[error("could not restore device '{0}' : {1}")]
+    InvalidDeviceState(String, String),
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+
+        let cpu = &self.cpu;
+        let mut payload = vec![];
+        for gr in cpu.gr {
+            payload.extend(gr.to_le_bytes());
+        }
+        for reg in [cpu.pc, cpu.sp, cpu.psr] {
+            payload.extend(reg.to_le_bytes());
+        }
+        payload.extend(cpu.tr.to_le_bytes());
+        for reg in [cpu.tlr, cpu.thr, cpu.ppc, cpu.ppsr] {
+            payload.extend(reg.to_le_bytes());
+        }
+        section(&mut out, CPU, &payload);
+        for (name, state) in &self.devices {
+            let mut payload = (name.len() as u32).to_le_bytes().to_vec();
+            payload.extend(name.as_bytes());
+            payload.extend(state);
+            section(&mut out, DEVICE, &payload);
+        }
+
+        let mut payload = (self.breakpoints.len() as u32).to_le_bytes().to_vec();
+        for b in &self.breakpoints {
+            payload.extend((b.id as u32).to_le_bytes());
+            payload.push(match b.kind {
+                BreakpointKind::Code => 0,
+                BreakpointKind::Watch(WatchKind::Write) => 1,
+                BreakpointKind::Watch(WatchKind::Read) => 2,
+                BreakpointKind::Watch(WatchKind::Access) => 3,
+            });
+            payload.extend(b.address.to_le_bytes());
+            payload.extend(b.len.to_le_bytes());
+            payload.push(b.enabled as u8);
+            payload.extend(b.hit_count.to_le_bytes());
+            payload.extend(b.ignore_count.to_le_bytes());
+            let text = b.condition.as_ref().map_or("", |c| c.text.as_str());
+            payload.extend((text.len() as u32).to_le_bytes());
+            payload.extend(text.as_bytes());
+        }
+        section(&mut out, BREAKPOINTS, &payload);
+        section(&mut out, STEPS, &self.steps.to_le_bytes());
+        section(&mut out, TIMER, &self.timer.prescale.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            Err(SnapshotError::BadMagic())?
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            Err(SnapshotError::UnsupportedVersion(version))?
+        }
+
+        let mut cpu = None;
+        let mut devices = vec![];
+        let mut breakpoints = None;
+        let mut steps = None;
+        let mut timer = None;
+        while !reader.is_empty() {
+            let tag = reader.take(4)?;
+            let len = reader.u32()? as usize;
+            let mut payload = Reader {
+                bytes: reader.take(len)?,
+                pos: 0,
+            };
+            match tag {
+                t if t == CPU => cpu = Some(decode_cpu(&mut payload)?),
+                t if t == DEVICE => {
+                    let len = payload.u32()? as usize;
+                    let name = String::from_utf8_lossy(payload.take(len)?).into_owned();
+                    devices.push((name, payload.bytes[payload.pos..].to_vec()));
+                }
+                t if t == BREAKPOINTS => breakpoints = Some(decode_breakpoints(&mut payload)?),
+                t if t == STEPS => steps = Some(payload.u64()?),
+                t if t == TIMER => {
+                    timer = Some(TimerState {
+                        prescale: payload.u32()?,
+                    })
+                }
+                _ => Err(SnapshotError::UnknownSection(tag_name(tag)))?,
+            }
+        }
+        let missing = |tag: &[u8; 4]| SnapshotError::MissingSection(tag_name(tag));
+        Ok(Snapshot {
+            cpu: cpu.ok_or_else(|| missing(CPU))?,
+            devices,
+            breakpoints: breakpoints.unwrap_or_default(),
+            steps: steps.unwrap_or_default(),
+            timer: timer.unwrap_or_default(),
+        })
+    }
+}
+
+fn section(out: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    out.extend(tag);
+    out.extend((payload.len() as u32).to_le_bytes());
+    out.extend(payload);
+}
+
+fn tag_name(tag: &[u8]) -> String {
+    String::from_utf8_lossy(tag).trim_end().to_string()
+}
+
+fn decode_cpu(reader: &mut Reader) -> Result<Cpu, SnapshotError> {
+    let mut cpu = Cpu::new();
+    for gr in cpu.gr.iter_mut() {
+        *gr = reader.u16()?;
+    }
+    cpu.pc = reader.u16()?;
+    cpu.sp = reader.u16()?;
+    cpu.psr = reader.u16()?;
+    cpu.tr = reader.u32()?;
+    cpu.tlr = reader.u16()?;
+    cpu.thr = reader.u16()?;
+    cpu.ppc = reader.u16()?;
+    cpu.ppsr = reader.u16()?;
+    Ok(cpu)
+}
+
+fn decode_breakpoints(reader: &mut Reader) -> Result<Vec<Breakpoint>, SnapshotError> {
+    let count = reader.u32()?;
+    let mut list = vec![];
+    for _ in 0..count {
+        let id = reader.u32()? as usize;
+        let kind = match reader.u8()? {
+            0 => BreakpointKind::Code,
+            1 => BreakpointKind::Watch(WatchKind::Write),
+            2 => BreakpointKind::Watch(WatchKind::Read),
+            3 => BreakpointKind::Watch(WatchKind::Access),
+            kind => Err(SnapshotError::InvalidBreakpoint(format!("kind {}", kind)))?,
+        };
+        let address = reader.u16()?;
+        let len = reader.u16()?;
+        let enabled = reader.u8()? != 0;
+        let hit_count = reader.u32()?;
+        let ignore_count = reader.u32()?;
+        let text_len = reader.u32()? as usize;
+        let text = String::from_utf8_lossy(reader.take(text_len)?).into_owned();
+        let condition = if text.is_empty() {
+            None
+        } else {
+            Some(
+                Condition::parse(&text)
+                    .map_err(|e| SnapshotError::InvalidBreakpoint(e.to_string()))?,
+            )
+        };
+        list.push(Breakpoint {
+            id,
+            kind,
+            address,
+            len,
+            enabled,
+            hit_count,
+            ignore_count,
+            condition,
+        });
+    }
+    Ok(list)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or(SnapshotError::Truncated())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let mut cpu = Cpu::new();
+        cpu.gr[3] = 0x1234;
+        cpu.tr = 0x12345678;
+        cpu.ppsr = 5;
+        Snapshot {
+            cpu,
+            devices: vec![
+                ("ram".to_string(), vec![4, 5]),
+                ("uart".to_string(), vec![]),
+                ("a".repeat(300), vec![6]),
+            ],
+            breakpoints: vec![Breakpoint {
+                id: 3,
+                kind: BreakpointKind::Watch(WatchKind::Read),
+                address: 0x8000,
+                len: 2,
+                enabled: false,
+                hit_count: 7,
+                ignore_count: 1,
+                condition: Some(Condition::parse("x1 == 2").unwrap()),
+            }],
+            steps: 1000,
+            timer: TimerState { prescale: 2 },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = snapshot();
+        assert_eq!(Snapshot::decode(&snapshot.encode()), Ok(snapshot));
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots() {
+        let bytes = snapshot().encode();
+        assert_eq!(
+            Snapshot::decode(b"ZKTCSNAQ\x01\x00"),
+            Err(SnapshotError::BadMagic())
+        );
+        let mut newer = bytes.clone();
+        newer[8] = 2;
+        assert_eq!(
+            Snapshot::decode(&newer),
+            Err(SnapshotError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            Snapshot::decode(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated())
+        );
+        let mut unknown = bytes.clone();
+        section(&mut unknown, b"ROM ", &[]);
+        assert_eq!(
+            Snapshot::decode(&unknown),
+            Err(SnapshotError::UnknownSection("ROM".to_string()))
+        );
+        assert_eq!(
+            Snapshot::decode(&bytes[..10]),
+            Err(SnapshotError::MissingSection("CPU".to_string()))
+        );
+    }
+}
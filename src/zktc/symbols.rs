@@ -0,0 +1,175 @@
+use super::asm::is_label;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+#[derive(thiserror::Error, Debug)]
+pub enum SymbolError {
+    #[error("could not read symbol file '{0}'")]
+    Io(String, #[source] std::io::Error),
+
+    #[error("line {0} : expected a label and an address, e.g. pass 0xb020")]
+    Syntax(usize),
+
+    #[error("line {0} : invalid label '{1}'")]
+    InvalidLabel(usize, String),
+
+    #[error("line {0} : invalid address '{1}'")]
+    InvalidAddress(usize, String),
+
+    #[error("line {0} : duplicate label '{1}'")]
+    DuplicateLabel(usize, String),
+}
+
+// Labels of a program and their addresses. An address with several labels is named after the
+// first one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    addresses: HashMap<String, u16>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl Symbols {
+    // One label and its address per line, the address in hex with or without 0x. Blank lines
+    // and lines starting with '#' or '//' are skipped.
+    //
+    //     pass 0xb020
+    //     fail 0xb026
+    pub fn parse(src: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Symbols::default();
+        for (i, line) in src.lines().enumerate() {
+            let line_num = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [label, address] = fields[..] else {
+                Err(SymbolError::Syntax(line_num))?
+            };
+            if !is_label(label) {
+                Err(SymbolError::InvalidLabel(line_num, label.to_string()))?
+            }
+            let hex = address.strip_prefix("0x").unwrap_or(address);
+            let Ok(address) = u16::from_str_radix(hex, 16) else {
+                Err(SymbolError::InvalidAddress(line_num, address.to_string()))?
+            };
+            if symbols.insert(label, address).is_some() {
+                Err(SymbolError::DuplicateLabel(line_num, label.to_string()))?
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| SymbolError::Io(path.display().to_string(), e))?;
+        Self::parse(&src)
+    }
+
+    // A label that is already defined keeps its address, which is returned.
+    pub fn insert(&mut self, label: &str, address: u16) -> Option<u16> {
+        if let Some(&previous) = self.addresses.get(label) {
+            return Some(previous);
+        }
+        self.addresses.insert(label.to_string(), address);
+        self.labels
+            .entry(address)
+            .or_insert_with(|| label.to_string());
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    // "fail" or "fail+0x4"
+    pub fn resolve(&self, name: &str) -> Option<u16> {
+        match name.split_once('+') {
+            Some((label, offset)) => {
+                let offset = u16::from_str_radix(offset.strip_prefix("0x")?, 16).ok()?;
+                Some(self.addresses.get(label)?.wrapping_add(offset))
+            }
+            None => self.addresses.get(name).copied(),
+        }
+    }
+
+    // Names an address after the closest label at or below it, "fail" or "fail+0x4".
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let (&base, label) = self.labels.range(..=address).next_back()?;
+        match address - base {
+            0 => Some(label.clone()),
+            offset => Some(format!("{}+0x{:x}", label, offset)),
+        }
+    }
+}
+
+// Writes the symbol file read by parse, ordered by address.
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut symbols: Vec<(&u16, &String)> =
+            self.addresses.iter().map(|(l, a)| (a, l)).collect();
+        symbols.sort();
+        for (address, label) in symbols {
+            writeln!(f, "{} 0x{:04x}", label, address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_symbols() {
+        let src = "
+            # from zktc-emu asm --symbols
+            expect 0xb01e
+            pass b020
+            fail 0xb026
+
+            finish 0xb02c
+        ";
+        let symbols = Symbols::parse(src).unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.resolve("pass"), Some(0xb020));
+        assert_eq!(symbols.resolve("fail+0x4"), Some(0xb02a));
+        assert_eq!(symbols.resolve("fail+4"), None);
+        assert_eq!(symbols.resolve("main"), None);
+        assert_eq!(symbols.describe(0xb026).as_deref(), Some("fail"));
+        assert_eq!(symbols.describe(0xb02a).as_deref(), Some("fail+0x4"));
+        assert_eq!(symbols.describe(0xb000), None);
+        assert_eq!(Symbols::parse(&symbols.to_string()).unwrap(), symbols);
+    }
+
+    #[test]
+    fn first_label_names_an_address() {
+        let mut symbols = Symbols::default();
+        assert_eq!(symbols.insert("loop", 0xb004), None);
+        assert_eq!(symbols.insert("start", 0xb004), None);
+        assert_eq!(symbols.insert("loop", 0xb008), Some(0xb004));
+        assert_eq!(symbols.describe(0xb006).as_deref(), Some("loop+0x2"));
+        assert_eq!(symbols.resolve("start"), Some(0xb004));
+    }
+
+    #[test]
+    fn invalid_symbols() {
+        let error = |src: &str| Symbols::parse(src).unwrap_err().to_string();
+        assert_eq!(
+            error("pass"),
+            "line 1 : expected a label and an address, e.g. pass 0xb020"
+        );
+        assert_eq!(error("\n1pass 0xb020"), "line 2 : invalid label '1pass'");
+        assert_eq!(error("pass 0x1b020"), "line 1 : invalid address '0x1b020'");
+        assert_eq!(
+            error("pass 0xb020\npass 0xb022"),
+            "line 2 : duplicate label 'pass'"
+        );
+    }
+}
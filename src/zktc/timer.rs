@@ -0,0 +1,137 @@
+use super::inst::Instruction;
+use std::fmt;
+use std::str::FromStr;
+
+// What the timer counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    Instruction,
+    Cycle,
+}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Clock::Instruction => write!(f, "instruction"),
+            Clock::Cycle => write!(f, "cycle"),
+        }
+    }
+}
+
+impl FromStr for Clock {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "instruction" => Ok(Clock::Instruction),
+            "cycle" => Ok(Clock::Cycle),
+            _ => Err(format!("unknown timer clock '{}'", s)),
+        }
+    }
+}
+
+// Changes while running, so it is kept in snapshots and the undo log.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TimerState {
+    // instructions or cycles since tr last advanced
+    pub prescale: u32,
+}
+
+// tr advances by one every `rate` instructions or cycles, a rate of 0 stops it. The timer
+// expires when tr overflows from 0xffffffff to 0, so writing 0 - n with wtr expires after n
+// counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timer {
+    pub clock: Clock,
+    pub rate: u32,
+    pub state: TimerState,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer {
+            clock: Clock::Instruction,
+            rate: 0,
+            state: TimerState::default(),
+        }
+    }
+}
+
+impl Timer {
+    // Counts one retired instruction which took `cycles` cycles. Returns whether the timer
+    // expired.
+    pub fn tick(&mut self, tr: &mut u32, cycles: u32) -> bool {
+        if self.rate == 0 {
+            return false;
+        }
+        let elapsed = match self.clock {
+            Clock::Instruction => 1,
+            Clock::Cycle => cycles,
+        };
+        let prescale = self.state.prescale as u64 + elapsed as u64;
+        self.state.prescale = (prescale % self.rate as u64) as u32;
+        let (value, overflow) = tr.overflowing_add((prescale / self.rate as u64) as u32);
+        *tr = value;
+        overflow
+    }
+
+    pub fn reset(&mut self) {
+        self.state = TimerState::default();
+    }
+}
+
+// Cycles taken by inst : memory accesses, jumps and taken branches take 2, the rest 1.
+pub fn cycles(inst: &Instruction, taken: bool) -> u32 {
+    use Instruction::*;
+
+    match inst {
+        Lh { .. } | Lhu { .. } | Lw { .. } | Sh { .. } | Sw { .. } | Push { .. } | Pop { .. } => 2,
+        Jal { .. } | Jalr { .. } | Rfi | Trap => 2,
+        Beq { .. } | Bnq { .. } | Blt { .. } | Bge { .. } | Bltu { .. } | Bgeu { .. } if taken => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts_at_rate() {
+        let mut timer = Timer {
+            clock: Clock::Cycle,
+            rate: 3,
+            state: TimerState::default(),
+        };
+        let mut tr = 0xffff_ffff;
+        assert!(!timer.tick(&mut tr, 2));
+        assert_eq!(tr, 0xffff_ffff);
+        assert!(timer.tick(&mut tr, 2));
+        assert_eq!((tr, timer.state.prescale), (0, 1));
+
+        timer.clock = Clock::Instruction;
+        assert!(!timer.tick(&mut tr, 2));
+        timer.tick(&mut tr, 2);
+        assert_eq!((tr, timer.state.prescale), (1, 0));
+        timer.rate = 0;
+        timer.tick(&mut tr, 2);
+        assert_eq!(tr, 1);
+
+        // off unless turned on
+        assert!(!Timer::default().tick(&mut tr, 2));
+        assert_eq!(tr, 1);
+    }
+
+    #[test]
+    fn cycles_of_instructions() {
+        let beq = Instruction::Beq {
+            rd: 1,
+            rs: 2,
+            imm: 4,
+        };
+        assert_eq!(cycles(&beq, false), 1);
+        assert_eq!(cycles(&beq, true), 2);
+        assert_eq!(cycles(&Instruction::Pop { rd: 1 }, false), 2);
+        assert_eq!(cycles(&Instruction::Mov { rd: 1, rs: 2 }, false), 1);
+    }
+}
@@ -0,0 +1,266 @@
+use super::expr::Reg;
+use super::inst::Instruction;
+use super::memory::MemoryAccess;
+use std::io::{self, Write};
+
+// One retired instruction.
+pub struct TraceEvent<'a> {
+    pub step: u64,
+    pub pc: u16,
+    pub word: u16,
+    pub inst: &'a Instruction,
+    pub regs: &'a [RegWrite],
+    pub memory: Option<MemoryRecord>,
+    // label of pc from the symbol file, e.g. "fail+0x4"
+    pub symbol: Option<String>,
+}
+
+// Registers written by the instruction, also when the value does not change. pc is left out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegWrite {
+    pub reg: Reg,
+    pub old: u32,
+    pub new: u32,
+}
+
+// Values of byte accesses are zero extended. old and new are equal for reads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryRecord {
+    pub access: MemoryAccess,
+    pub old: u16,
+    pub new: u16,
+}
+
+pub trait TraceSink: Send {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+pub fn written_registers(inst: &Instruction) -> Vec<Reg> {
+    use Instruction::*;
+
+    let gr = |rd: u8| if rd == 0 { vec![] } else { vec![Reg::Gr(rd)] };
+    match *inst {
+        Mov { rd, .. }
+        | Add { rd, .. }
+        | Sub { rd, .. }
+        | And { rd, .. }
+        | Or { rd, .. }
+        | Xor { rd, .. }
+        | Sll { rd, .. }
+        | Srl { rd, .. }
+        | Sra { rd, .. }
+        | Addi { rd, .. }
+        | Subi { rd, .. }
+        | Jalr { rd, .. }
+        | Lh { rd, .. }
+        | Lhu { rd, .. }
+        | Lw { rd, .. }
+        | Jal { rd, .. }
+        | Lil { rd, .. }
+        | Lih { rd, .. }
+        | Rpc { rd }
+        | Rsp { rd }
+        | Rpsr { rd }
+        | Rtlr { rd }
+        | Rthr { rd }
+        | Rppc { rd }
+        | Rppsr { rd } => gr(rd),
+        Pop { rd } => [gr(rd), vec![Reg::Sp]].concat(),
+        Push { .. } | Wsp { .. } => vec![Reg::Sp],
+        Wpsr { .. } | Rfi => vec![Reg::Psr],
+        Wtlr { .. } => vec![Reg::Tlr],
+        Wthr { .. } => vec![Reg::Thr],
+        Wppc { .. } => vec![Reg::Ppc],
+        Wppsr { .. } => vec![Reg::Ppsr],
+        Rtr => vec![Reg::Tlr, Reg::Thr],
+        Wtr => vec![Reg::Tr],
+        Trap => vec![Reg::Psr, Reg::Ppc, Reg::Ppsr],
+        Beq { .. }
+        | Bnq { .. }
+        | Blt { .. }
+        | Bge { .. }
+        | Bltu { .. }
+        | Bgeu { .. }
+        | Sh { .. }
+        | Sw { .. } => vec![],
+    }
+}
+
+// "pc : 0xb000 0000100000100001 addi x1 x0 1", followed by " <fail+0x4>" when pc has a label
+pub fn format_text(event: &TraceEvent) -> String {
+    let line = format!("pc : 0x{:04x} {:016b} {}", event.pc, event.word, event.inst);
+    match &event.symbol {
+        Some(symbol) => format!("{} <{}>", line, symbol),
+        None => line,
+    }
+}
+
+// {"step":0,"pc":45056,"word":2081,"inst":"addi x1 x0 1","regs":[{"reg":"x1","old":0,"new":1}],"mem":[]}
+// with "symbol":"fail+0x4" at the end when pc has a label
+pub fn format_jsonl(event: &TraceEvent) -> String {
+    let regs: Vec<String> = event
+        .regs
+        .iter()
+        .map(|w| format!(r#"{{"reg":"{}","old":{},"new":{}}}"#, w.reg, w.old, w.new))
+        .collect();
+    let mem: Vec<String> = event
+        .memory
+        .iter()
+        .map(|m| {
+            format!(
+                r#"{{"addr":{},"size":{},"write":{},"old":{},"new":{}}}"#,
+                m.access.address,
+                m.access.size(),
+                m.access.write,
+                m.old,
+                m.new
+            )
+        })
+        .collect();
+    let symbol = match &event.symbol {
+        Some(symbol) => format!(r#","symbol":"{}""#, symbol),
+        None => String::new(),
+    };
+    format!(
+        r#"{{"step":{},"pc":{},"word":{},"inst":"{}","regs":[{}],"mem":[{}]{}}}"#,
+        event.step,
+        event.pc,
+        event.word,
+        event.inst,
+        regs.join(","),
+        mem.join(","),
+        symbol
+    )
+}
+
+// Writes the human readable trace, one line per instruction.
+pub struct TextSink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> TextSink<W> {
+    pub fn new(out: W) -> Self {
+        TextSink { out }
+    }
+}
+
+impl TextSink<io::Stdout> {
+    pub fn stdout() -> Self {
+        TextSink::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send> TraceSink for TextSink<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        // a closed pipe must not stop the emulator
+        let _ = writeln!(self.out, "{}", format_text(event));
+    }
+}
+
+// Writes one JSON object per instruction.
+pub struct JsonlSink<W: Write + Send> {
+    out: W,
+}
+
+impl<W: Write + Send> JsonlSink<W> {
+    pub fn new(out: W) -> Self {
+        JsonlSink { out }
+    }
+}
+
+impl<W: Write + Send> TraceSink for JsonlSink<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let _ = writeln!(self.out, "{}", format_jsonl(event));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_sink_test() {
+        let inst = Instruction::Addi {
+            rd: 1,
+            rs: 0,
+            imm: 1,
+        };
+        let mut sink = TextSink::new(vec![]);
+        sink.trace(&TraceEvent {
+            step: 0,
+            pc: 0xb000,
+            word: 0x0821,
+            inst: &inst,
+            regs: &[],
+            memory: None,
+            symbol: None,
+        });
+        sink.trace(&TraceEvent {
+            step: 1,
+            pc: 0xb02a,
+            word: 0x0821,
+            inst: &inst,
+            regs: &[],
+            memory: None,
+            symbol: Some("fail+0x4".to_string()),
+        });
+        assert_eq!(
+            String::from_utf8(sink.out).unwrap(),
+            "pc : 0xb000 0000100000100001 addi x1 x0 1\n\
+             pc : 0xb02a 0000100000100001 addi x1 x0 1 <fail+0x4>\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_sink_test() {
+        let inst = Instruction::Push { rd: 1 };
+        let mut sink = JsonlSink::new(vec![]);
+        sink.trace(&TraceEvent {
+            step: 3,
+            pc: 0xb006,
+            word: 0x083e,
+            inst: &inst,
+            regs: &[RegWrite {
+                reg: Reg::Sp,
+                old: 0x8000,
+                new: 0x7ffe,
+            }],
+            memory: Some(MemoryRecord {
+                access: MemoryAccess {
+                    address: 0x7ffe,
+                    half: false,
+                    write: true,
+                },
+                old: 0,
+                new: 5,
+            }),
+            symbol: Some("loop+0x6".to_string()),
+        });
+        assert_eq!(
+            String::from_utf8(sink.out).unwrap(),
+            concat!(
+                r#"{"step":3,"pc":45062,"word":2110,"inst":"push x1","#,
+                r#""regs":[{"reg":"sp","old":32768,"new":32766}],"#,
+                r#""mem":[{"addr":32766,"size":2,"write":true,"old":0,"new":5}],"#,
+                r#""symbol":"loop+0x6"}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn written_registers_test() {
+        assert_eq!(
+            written_registers(&Instruction::Mov { rd: 0, rs: 1 }),
+            vec![]
+        );
+        assert_eq!(
+            written_registers(&Instruction::Pop { rd: 2 }),
+            vec![Reg::Gr(2), Reg::Sp]
+        );
+        assert_eq!(
+            written_registers(&Instruction::Trap),
+            vec![Reg::Psr, Reg::Ppc, Reg::Ppsr]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use super::bus::Device;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub const DEFAULT_BASE: u16 = 0x9000;
+
+// Register offsets. Reading DATA takes a received byte, writing it sends one.
+pub const DATA: u16 = 0;
+pub const STATUS: u16 = 2;
+pub const CONTROL: u16 = 4;
+pub const SIZE: u16 = 6;
+
+pub const STATUS_RX_READY: u8 = 0x1;
+pub const STATUS_TX_READY: u8 = 0x2;
+pub const CONTROL_RX_INTERRUPT: u16 = 0x1;
+
+// received bytes not read yet, more wait in the channel
+const FIFO_SIZE: usize = 16;
+
+pub struct Uart {
+    tx: Box<dyn Write + Send>,
+    rx: Option<Receiver<u8>>,
+    fifo: VecDeque<u8>,
+    control: u16,
+}
+
+impl Uart {
+    // Without rx nothing is ever received.
+    pub fn new(tx: Box<dyn Write + Send>, rx: Option<Receiver<u8>>) -> Self {
+        Uart {
+            tx,
+            rx,
+            fifo: VecDeque::new(),
+            control: 0,
+        }
+    }
+
+    // Sends to stdout and receives from stdin if rx is set.
+    pub fn stdio(rx: bool) -> Self {
+        let rx = rx.then(|| spawn_reader(io::stdin()));
+        Uart::new(Box::new(io::stdout()), rx)
+    }
+
+    pub fn file(path: &str) -> io::Result<Self> {
+        Ok(Uart::new(
+            Box::new(io::BufWriter::new(File::create(path)?)),
+            None,
+        ))
+    }
+
+    // Opens a pseudo-terminal and returns the uart with the path of the terminal to connect to,
+    // e.g. with screen.
+    #[cfg(unix)]
+    pub fn pty() -> io::Result<(Self, String)> {
+        let (master, path) = pty::open()?;
+        let rx = spawn_reader(master.try_clone()?);
+        Ok((Uart::new(Box::new(master), Some(rx)), path))
+    }
+
+    fn status(&self) -> u8 {
+        let rx_ready = if self.fifo.is_empty() {
+            0
+        } else {
+            STATUS_RX_READY
+        };
+        rx_ready | STATUS_TX_READY
+    }
+}
+
+// Reads bytes on a thread so that the emulator never blocks on input.
+pub fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 64];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            if buf[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+impl Device for Uart {
+    fn read8(&mut self, offset: u16) -> u8 {
+        match offset {
+            DATA => self.fifo.pop_front().unwrap_or(0),
+            _ => self.peek8(offset),
+        }
+    }
+
+    fn write8(&mut self, offset: u16, data: u8) {
+        match offset {
+            DATA => {
+                // the program cannot do anything about a closed terminal
+                let _ = self.tx.write_all(&[data]).and_then(|_| self.tx.flush());
+            }
+            CONTROL => self.control = (self.control & 0xff00) | data as u16,
+            o if o == CONTROL + 1 => self.control = (self.control & 0x00ff) | (data as u16) << 8,
+            _ => {}
+        }
+    }
+
+    fn peek8(&self, offset: u16) -> u8 {
+        match offset {
+            DATA => self.fifo.front().copied().unwrap_or(0),
+            STATUS => self.status(),
+            CONTROL => self.control as u8,
+            o if o == CONTROL + 1 => (self.control >> 8) as u8,
+            _ => 0,
+        }
+    }
+
+    fn tick(&mut self) {
+        let Some(rx) = &self.rx else {
+            return;
+        };
+        while self.fifo.len() < FIFO_SIZE {
+            match rx.try_recv() {
+                Ok(byte) => self.fifo.push_back(byte),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.fifo.clear();
+        self.control = 0;
+    }
+
+    fn irq(&self) -> bool {
+        self.control & CONTROL_RX_INTERRUPT != 0 && !self.fifo.is_empty()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.control.to_le_bytes().to_vec();
+        state.extend(&self.fifo);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let [low, high, fifo @ ..] = state else {
+            return Err("uart state is too short".to_string());
+        };
+        self.control = u16::from_le_bytes([*low, *high]);
+        self.fifo = fifo.iter().copied().collect();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::FromRawFd;
+
+    // The slave side is kept open so that reading the master does not fail while no terminal
+    // is connected, and is in raw mode so that bytes pass unchanged.
+    pub fn open() -> io::Result<(File, String)> {
+        unsafe {
+            let master = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if master < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master_file = File::from_raw_fd(master);
+            if libc::grantpt(master) != 0 || libc::unlockpt(master) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut name = [0 as libc::c_char; 128];
+            if libc::ptsname_r(master, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+
+            let slave = libc::open(name.as_ptr(), libc::O_RDWR | libc::O_NOCTTY);
+            if slave < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(slave, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(slave, libc::TCSANOW, &termios);
+            }
+            // leaked on purpose, see above
+            std::mem::forget(File::from_raw_fd(slave));
+            Ok((master_file, path))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    // A Write that can be looked at after the uart took it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn transmit_and_receive() {
+        let out = Shared::default();
+        let (sender, receiver) = mpsc::channel();
+        let mut uart = Uart::new(Box::new(out.clone()), Some(receiver));
+
+        uart.write16(DATA, 'h' as u16);
+        uart.write8(DATA, b'i');
+        assert_eq!(*out.0.lock().unwrap(), b"hi");
+
+        assert_eq!(uart.peek8(STATUS), STATUS_TX_READY);
+        sender.send(b'a').unwrap();
+        sender.send(b'b').unwrap();
+        assert_eq!(uart.peek8(STATUS), STATUS_TX_READY);
+        uart.tick();
+        assert_eq!(uart.peek8(STATUS), STATUS_TX_READY | STATUS_RX_READY);
+        assert_eq!(uart.peek8(DATA), b'a');
+        assert_eq!(uart.read16(DATA), b'a' as u16);
+        assert_eq!(uart.read8(DATA), b'b');
+        assert_eq!(uart.read8(DATA), 0);
+        assert_eq!(uart.peek8(STATUS), STATUS_TX_READY);
+    }
+
+    #[test]
+    fn rx_interrupt() {
+        let (sender, receiver) = mpsc::channel();
+        let mut uart = Uart::new(Box::new(io::sink()), Some(receiver));
+        sender.send(b'x').unwrap();
+        uart.tick();
+        assert!(!uart.irq());
+        uart.write16(CONTROL, CONTROL_RX_INTERRUPT);
+        assert!(uart.irq());
+
+        let state = uart.save_state();
+        uart.read8(DATA);
+        assert!(!uart.irq());
+        uart.load_state(&state).unwrap();
+        assert!(uart.irq());
+        uart.reset();
+        assert_eq!((uart.irq(), uart.peek16(CONTROL)), (false, 0));
+    }
+}